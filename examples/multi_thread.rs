@@ -17,6 +17,8 @@ fn main() {
       checkpoint_count: 10000,
       group_commit_delay: Duration::from_millis(10),
       group_commit_count: 100,
+      max_key_size: 256,
+      lock_timeout: Duration::from_secs(3),
     })
     .unwrap(),
   );
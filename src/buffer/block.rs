@@ -2,6 +2,8 @@ use crate::{size, Error, Page, Serializable, PAGE_SIZE};
 
 pub const BLOCK_SIZE: usize = size::kb(4);
 
+/// Laid out on disk as commit index (8), tx id (8), undo tag (1),
+/// undo index (8, only when the tag is 1) and the page.
 #[derive(Debug)]
 pub struct DataBlock {
   pub commit_index: usize,
@@ -29,6 +31,10 @@ impl DataBlock {
     }
   }
 
+  pub fn is_visible(&self, tx_id: usize, snapshot: usize) -> bool {
+    self.tx_id.eq(&tx_id) || (self.commit_index.ne(&0) && self.commit_index.le(&snapshot))
+  }
+
   pub fn copy(&self) -> Self {
     Self::new(
       self.commit_index,
@@ -44,14 +50,21 @@ impl Serializable<Error, BLOCK_SIZE> for DataBlock {
     let mut wt = page.writer();
     wt.write(self.commit_index.to_be_bytes().as_ref())?;
     wt.write(self.tx_id.to_be_bytes().as_ref())?;
-    if let Some(i) = self.undo_index {
-      wt.write(&[1u8])?;
-      wt.write(i.to_be_bytes().as_ref())?;
+    match self.undo_index {
+      Some(i) => {
+        wt.write(&[1u8])?;
+        wt.write(i.to_be_bytes().as_ref())?;
+      }
+      None => wt.write(&[0u8])?,
     }
     wt.write(self.data.as_ref())?;
     Ok(page)
   }
   fn deserialize(value: &Page<BLOCK_SIZE>) -> std::prelude::v1::Result<Self, Error> {
+    if value.is_empty() {
+      return Err(Error::NotFound);
+    }
+
     let mut sc = value.scanner();
     let commit_index = sc.read_usize()?;
     let tx_id = sc.read_usize()?;
@@ -64,3 +77,31 @@ impl Serializable<Error, BLOCK_SIZE> for DataBlock {
     Ok(Self::new(commit_index, tx_id, undo_index, data))
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use crate::{Error, Page, Serializable};
+
+  use super::{DataBlock, BLOCK_SIZE};
+
+  #[test]
+  fn _1() {
+    let mut data = Page::new();
+    data.writer().write(&[1, 2, 3]).unwrap();
+
+    for undo_index in [None, Some(7)] {
+      let block = DataBlock::new(3, 5, undo_index, data.copy());
+      let decoded: DataBlock = block.serialize().unwrap().deserialize().unwrap();
+      assert_eq!(decoded.commit_index, 3);
+      assert_eq!(decoded.tx_id, 5);
+      assert_eq!(decoded.undo_index, undo_index);
+      assert_eq!(decoded.data, data);
+    }
+
+    let empty = Page::<BLOCK_SIZE>::new_empty();
+    assert!(matches!(
+      empty.deserialize::<DataBlock, Error>(),
+      Err(Error::NotFound)
+    ));
+  }
+}
@@ -1,7 +1,8 @@
 use std::{
-  collections::BTreeMap,
-  ops::Mul,
+  collections::{BTreeMap, BTreeSet},
+  ops::{AddAssign, Mul},
   sync::{Arc, Mutex},
+  time::{Duration, SystemTime},
 };
 
 use crate::{
@@ -9,19 +10,21 @@ use crate::{
   ShortenedMutex,
 };
 
-use super::{CacheStorage, DataBlock, RollbackStorage, BLOCK_SIZE};
+use super::{CacheStorage, DataBlock, PageLocks, RollbackStorage, BLOCK_SIZE};
 
 pub struct BufferPool {
   cache: Arc<CacheStorage>,
   rollback: Arc<RollbackStorage>,
   uncommitted: Arc<Mutex<BTreeMap<usize, Vec<usize>>>>,
   disk: Arc<Finder<BLOCK_SIZE>>,
+  locks: PageLocks,
 }
 impl BufferPool {
   pub fn generate(
     rollback: Arc<RollbackStorage>,
     disk: Arc<Finder<BLOCK_SIZE>>,
     max_cache_size: usize,
+    lock_timeout: Duration,
   ) -> (
     Self,
     BackgroundThread<(), Option<usize>>,
@@ -79,7 +82,7 @@ impl BufferPool {
                 let mut block: DataBlock = disk_cloned.read(index)?.deserialize()?;
                 if block.tx_id.eq(&commit.tx_id) {
                   block.commit_index = commit.commit_index;
-                  cache_cloned.insert(index, block);
+                  cache_cloned.insert_new(index, block);
                   continue;
                 }
 
@@ -104,6 +107,7 @@ impl BufferPool {
         rollback,
         uncommitted,
         disk,
+        locks: PageLocks::new(lock_timeout),
       },
       flush_c,
       commit_c,
@@ -121,7 +125,7 @@ impl BufferPool {
   //   });
   // }
 
-  pub fn get(&self, commit_index: usize, index: usize) -> Result<Page> {
+  pub fn get(&self, tx_id: usize, snapshot: usize, index: usize) -> Result<Page> {
     let block = {
       match self.cache.get(&index) {
         Some(block) => block.copy(),
@@ -133,33 +137,212 @@ impl BufferPool {
       }
     };
 
-    if block.commit_index.le(&commit_index) {
+    if block.is_visible(tx_id, snapshot) {
       return Ok(block.data.copy());
     }
     match block.undo_index {
-      Some(i) => self.rollback.get(commit_index, i),
+      Some(i) => self.rollback.get(tx_id, snapshot, i),
       None => Err(Error::NotFound),
     }
   }
 
+  pub fn get_latest(&self, tx_id: usize, index: usize) -> Result<Page> {
+    self.get(tx_id, usize::MAX, index)
+  }
+
+  pub fn lock(&self, tx_id: usize, index: usize) -> Result<()> {
+    self.locks.acquire(tx_id, index)
+  }
+
+  pub fn try_lock(&self, tx_id: usize, index: usize) -> Result<()> {
+    match self.locks.try_acquire(tx_id, index) {
+      true => Ok(()),
+      false => Err(Error::PageLocked(index)),
+    }
+  }
+
+  pub fn wait_unlocked(&self, tx_id: usize, index: usize) -> Result<()> {
+    self.locks.wait(tx_id, index)
+  }
+
+  pub fn unlock(&self, tx_id: usize, index: usize) {
+    self.locks.release(tx_id, index)
+  }
+
+  pub fn unlock_all(&self, tx_id: usize) {
+    self.locks.release_all(tx_id)
+  }
+
+  /// The page lock is held until the transaction ends, and versions of
+  /// an aborted one are undone before its locks are released, so the
+  /// latest version is always committed or the transaction's own.
   pub fn insert(&self, tx_id: usize, index: usize, data: Page) -> Result<()> {
-    let undo_index = {
-      match self.cache.get(&index) {
-        Some(block) => Some(self.rollback.append(block.copy())?),
-        None => match self.disk.read(index)?.deserialize::<DataBlock, Error>() {
-          Ok(block) => Some(self.rollback.append(block)?),
-          Err(Error::NotFound) => None,
-          Err(err) => return Err(err),
-        },
-      }
-    };
+    self.locks.acquire(tx_id, index)?;
+    let mut uncommitted = self.uncommitted.l();
+    let latest = self.latest(index)?;
+    let undo_index = self.append_undo(tx_id, latest)?;
 
     let new_block = DataBlock::uncommitted(tx_id, undo_index, data);
     self.cache.insert_new(index, new_block);
-    self.uncommitted.l().entry(tx_id).or_default().push(index);
+    uncommitted.entry(tx_id).or_default().push(index);
     Ok(())
   }
 
+  pub fn replay(
+    &self,
+    tx_id: usize,
+    commit_index: usize,
+    index: usize,
+    data: Page,
+  ) -> Result<()> {
+    let undo_index = self.append_undo(tx_id, self.latest(index)?)?;
+    let block = DataBlock::new(commit_index, tx_id, undo_index, data);
+    self.cache.insert_new(index, block);
+    self.rollback.finish(tx_id, commit_index);
+    Ok(())
+  }
+
+  fn append_undo(
+    &self,
+    tx_id: usize,
+    latest: Option<DataBlock>,
+  ) -> Result<Option<usize>> {
+    match latest {
+      Some(block) => Ok(Some(self.rollback.append(tx_id, block)?)),
+      None => Ok(None),
+    }
+  }
+
+  fn latest(&self, index: usize) -> Result<Option<DataBlock>> {
+    if let Some(block) = self.cache.get(&index) {
+      return Ok(Some(block));
+    }
+    match self.disk.read(index)?.deserialize::<DataBlock, Error>() {
+      Ok(block) => Ok(Some(block)),
+      Err(Error::NotFound) => Ok(None),
+      Err(err) => Err(err),
+    }
+  }
+
+  pub fn rollback(&self, tx_id: usize) -> Result<()> {
+    let indexes: BTreeSet<usize> = match self.uncommitted.l().remove(&tx_id) {
+      Some(v) => v.into_iter().collect(),
+      None => return Ok(()),
+    };
+
+    for index in indexes {
+      let block = match self.latest(index)? {
+        Some(block) => block,
+        None => continue,
+      };
+      if block.tx_id.ne(&tx_id) || block.commit_index.ne(&0) {
+        continue;
+      }
+
+      let restored = match block.undo_index {
+        Some(i) => self.rollback.restore(tx_id, i)?,
+        None => None,
+      };
+      match restored {
+        Some(block) => self.cache.insert_new(index, block),
+        None => {
+          self.cache.remove(&index);
+          self.disk.write(index, Page::new_empty())?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  pub fn savepoint(&self, tx_id: usize) -> usize {
+    self.uncommitted.l().get(&tx_id).map(Vec::len).unwrap_or(0)
+  }
+
+  /// Undoes the writes made after the savepoint one version at a time,
+  /// returns the restored pages, None for the ones that did not exist.
+  pub fn rollback_to(
+    &self,
+    tx_id: usize,
+    savepoint: usize,
+  ) -> Result<Vec<(usize, Option<Page>)>> {
+    let undone = match self.uncommitted.l().get_mut(&tx_id) {
+      Some(v) if v.len().lt(&savepoint) => return Err(Error::Invalid),
+      Some(v) => v.split_off(savepoint),
+      None if savepoint.gt(&0) => return Err(Error::Invalid),
+      None => return Ok(vec![]),
+    };
+    let mut counts: BTreeMap<usize, usize> = BTreeMap::new();
+    for index in undone {
+      counts.entry(index).or_default().add_assign(1);
+    }
+
+    let mut restored = vec![];
+    for (index, count) in counts {
+      let mut block = self.latest(index)?;
+      for _ in 0..count {
+        block = match block.and_then(|b| b.undo_index) {
+          Some(i) => Some(self.rollback.get_block(i)?),
+          None => None,
+        };
+      }
+      match block {
+        Some(block) => {
+          restored.push((index, Some(block.data.copy())));
+          self.cache.insert_new(index, block);
+        }
+        None => {
+          restored.push((index, None));
+          self.cache.remove(&index);
+          self.disk.write(index, Page::new_empty())?;
+        }
+      }
+    }
+    Ok(restored)
+  }
+
+  /// Reads the snapshot and keeps the versions it can see from being reclaimed.
+  pub fn pin_snapshot<F>(&self, snapshot: F) -> usize
+  where
+    F: FnOnce() -> usize,
+  {
+    self.rollback.pin(snapshot)
+  }
+
+  pub fn pin_snapshot_at(&self, snapshot: usize) -> Result<usize> {
+    self.rollback.pin_at(snapshot)
+  }
+
+  pub fn unpin_snapshot(&self, snapshot: usize) {
+    self.rollback.unpin(snapshot)
+  }
+
+  pub fn oldest_snapshot(&self) -> usize {
+    self.rollback.oldest()
+  }
+
+  pub fn retain_with<T, F>(&self, f: F) -> T
+  where
+    F: FnOnce(usize) -> (T, usize),
+  {
+    self.rollback.retain_with(f)
+  }
+
+  pub fn finish(&self, tx_id: usize, commit_index: usize) {
+    self.rollback.finish(tx_id, commit_index)
+  }
+
+  pub fn start_history(&self, commit_index: usize) {
+    self.rollback.start_history(commit_index)
+  }
+
+  pub fn commit_index_at(&self, time: SystemTime) -> Option<usize> {
+    self.rollback.commit_index_at(time)
+  }
+
+  pub fn discard(&self, index: usize) {
+    self.cache.remove(&index);
+  }
+
   pub fn before_shutdown(&self) {
     self.cache.before_shutdown();
     self.rollback.destroy();
@@ -66,6 +66,13 @@ impl CacheStorage {
     }
   }
 
+  pub fn remove(&self, index: &usize) {
+    let mut core = self.0.l();
+    core.dirty.remove(index);
+    core.evicted.remove(index);
+    core.cache.remove(index);
+  }
+
   pub fn commit(
     &self,
     index: usize,
@@ -75,6 +82,7 @@ impl CacheStorage {
     if let Some(block) = core.cache.get_mut(&index) {
       if block.tx_id.eq(&commit.tx_id) {
         block.commit_index = commit.commit_index;
+        core.dirty.insert(index);
         return Ok(true);
       }
 
@@ -84,6 +92,7 @@ impl CacheStorage {
     if let Some(block) = core.evicted.get_mut(&index) {
       if block.tx_id.eq(&commit.tx_id) {
         block.commit_index = commit.commit_index;
+        core.dirty.insert(index);
         return Ok(true);
       }
 
@@ -0,0 +1,142 @@
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  sync::{Condvar, Mutex},
+  time::{Duration, Instant},
+};
+
+use crate::{Error, Result, ShortenedMutex};
+
+#[derive(Default)]
+struct PageLocksCore {
+  owners: BTreeMap<usize, usize>,
+  owned: BTreeMap<usize, BTreeSet<usize>>,
+}
+
+/// Page write locks, held by a transaction until it commits or aborts.
+pub struct PageLocks {
+  core: Mutex<PageLocksCore>,
+  released: Condvar,
+  timeout: Duration,
+}
+impl PageLocks {
+  pub fn new(timeout: Duration) -> Self {
+    Self {
+      core: Default::default(),
+      released: Condvar::new(),
+      timeout,
+    }
+  }
+
+  pub fn acquire(&self, tx_id: usize, index: usize) -> Result {
+    let deadline = Instant::now() + self.timeout;
+    let mut core = self.core.l();
+    loop {
+      match core.owners.get(&index) {
+        None => {
+          core.owners.insert(index, tx_id);
+          core.owned.entry(tx_id).or_default().insert(index);
+          return Ok(());
+        }
+        Some(owner) if owner.eq(&tx_id) => return Ok(()),
+        Some(_) => {}
+      }
+
+      let now = Instant::now();
+      if now.ge(&deadline) {
+        return Err(Error::LockTimeout);
+      }
+      core = self.released.wait_timeout(core, deadline - now).unwrap().0;
+    }
+  }
+
+  /// Takes the lock only if no other transaction holds it.
+  pub fn try_acquire(&self, tx_id: usize, index: usize) -> bool {
+    let mut core = self.core.l();
+    match core.owners.get(&index) {
+      Some(owner) => owner.eq(&tx_id),
+      None => {
+        core.owners.insert(index, tx_id);
+        core.owned.entry(tx_id).or_default().insert(index);
+        true
+      }
+    }
+  }
+
+  /// Waits until no other transaction holds the lock, without taking it.
+  pub fn wait(&self, tx_id: usize, index: usize) -> Result {
+    let deadline = Instant::now() + self.timeout;
+    let mut core = self.core.l();
+    loop {
+      match core.owners.get(&index) {
+        Some(owner) if owner.ne(&tx_id) => {}
+        _ => return Ok(()),
+      }
+
+      let now = Instant::now();
+      if now.ge(&deadline) {
+        return Err(Error::LockTimeout);
+      }
+      core = self.released.wait_timeout(core, deadline - now).unwrap().0;
+    }
+  }
+
+  pub fn release(&self, tx_id: usize, index: usize) {
+    let mut core = self.core.l();
+    if core.owners.get(&index).ne(&Some(&tx_id)) {
+      return;
+    }
+    core.owners.remove(&index);
+    if let Some(owned) = core.owned.get_mut(&tx_id) {
+      owned.remove(&index);
+    }
+    self.released.notify_all();
+  }
+
+  pub fn release_all(&self, tx_id: usize) {
+    let mut core = self.core.l();
+    for index in core.owned.remove(&tx_id).unwrap_or_default() {
+      core.owners.remove(&index);
+    }
+    self.released.notify_all();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{sync::Arc, thread, time::Duration};
+
+  use crate::Error;
+
+  use super::PageLocks;
+
+  #[test]
+  fn _1() {
+    let locks = PageLocks::new(Duration::from_millis(10));
+    locks.acquire(1, 3).unwrap();
+    locks.acquire(1, 3).unwrap();
+    assert!(matches!(locks.acquire(2, 3), Err(Error::LockTimeout)));
+    locks.acquire(2, 4).unwrap();
+
+    locks.release(2, 3);
+    assert!(matches!(locks.acquire(2, 3), Err(Error::LockTimeout)));
+    locks.release_all(1);
+    locks.acquire(2, 3).unwrap();
+  }
+
+  #[test]
+  fn _2() {
+    let locks = Arc::new(PageLocks::new(Duration::from_secs(5)));
+    assert!(locks.try_acquire(1, 3));
+    assert!(locks.try_acquire(1, 3));
+    assert!(!locks.try_acquire(2, 3));
+    locks.wait(1, 3).unwrap();
+
+    let l = locks.clone();
+    let waiter = thread::spawn(move || {
+      l.wait(2, 3).unwrap();
+      l.try_acquire(2, 3)
+    });
+    locks.release(1, 3);
+    assert!(waiter.join().unwrap());
+  }
+}
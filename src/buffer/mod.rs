@@ -7,6 +7,9 @@ use cache::*;
 mod buffer_pool;
 pub use buffer_pool::*;
 
+mod locks;
+use locks::*;
+
 mod list;
 
 mod undo;
@@ -1,20 +1,26 @@
 use std::{
-  ops::{Add, DivAssign},
+  collections::{BTreeMap, VecDeque},
+  ops::{Add, AddAssign, DivAssign, Sub, SubAssign},
   path::PathBuf,
-  sync::Mutex,
-  time::Duration,
+  sync::{Mutex, RwLock},
+  time::{Duration, SystemTime},
 };
 
 use crate::{
   disk::{Finder, FinderConfig},
+  logger,
   wal::CommitInfo,
-  Error, Page, Result, Serializable, ShortenedMutex, PAGE_SIZE,
+  Error, Page, Result, Serializable, ShortenedMutex, ShortenedRwLock, PAGE_SIZE,
 };
 
 use super::{DataBlock, LRUCache};
 
-const UNDO_PAGE_SIZE: usize = PAGE_SIZE + 32;
+const UNDO_PAGE_SIZE: usize = PAGE_SIZE + 40;
+/// Commits older than the last ones mapped to a time can only be read by index.
+const MAX_HISTORY: usize = 4096;
 
+/// Laid out like a data block with its own index in front,
+/// the undo tag is always written so the page is never read as a tag.
 #[derive(Debug)]
 pub struct UndoLog {
   index: usize,
@@ -41,6 +47,10 @@ impl UndoLog {
     }
   }
 
+  fn is_visible(&self, tx_id: usize, snapshot: usize) -> bool {
+    self.tx_id.eq(&tx_id) || (self.commit_index.ne(&0) && self.commit_index.le(&snapshot))
+  }
+
   fn from_data(index: usize, data: DataBlock) -> Self {
     Self::new(
       index,
@@ -81,9 +91,12 @@ impl Serializable<Error, UNDO_PAGE_SIZE> for UndoLog {
     wt.write(&self.index.to_be_bytes())?;
     wt.write(&self.commit_index.to_be_bytes())?;
     wt.write(&self.tx_id.to_be_bytes())?;
-    if let Some(i) = self.undo_index {
-      wt.write(&[1])?;
-      wt.write(&i.to_be_bytes())?;
+    match self.undo_index {
+      Some(i) => {
+        wt.write(&[1])?;
+        wt.write(&i.to_be_bytes())?;
+      }
+      None => wt.write(&[0])?,
     }
     wt.write(self.data.as_ref())?;
 
@@ -94,10 +107,10 @@ impl Serializable<Error, UNDO_PAGE_SIZE> for UndoLog {
     let index = sc.read_usize()?;
     let commit_index = sc.read_usize()?;
     let tx_id = sc.read_usize()?;
-    let undo_index = if sc.read()?.eq(&1) {
-      Some(sc.read_usize()?)
-    } else {
-      None
+    let undo_index = match sc.read()? {
+      0 => None,
+      1 => Some(sc.read_usize()?),
+      _ => return Err(Error::Invalid),
     };
     let data = sc.read_n(PAGE_SIZE)?.into();
 
@@ -113,11 +126,69 @@ pub struct RollbackStorageConfig {
   pub path: PathBuf,
 }
 
+/// The records which are not reclaimed yet, in the order they were appended,
+/// with the tx id and the file slot of each.
+struct UndoRing {
+  capacity: usize,
+  cursor: usize,
+  records: VecDeque<(usize, usize, usize)>,
+  writers: BTreeMap<usize, usize>,
+  /// slots of reclaimed records, reused before the file grows.
+  free: Vec<usize>,
+  used: usize,
+}
+impl UndoRing {
+  fn slot(&self, index: usize) -> Option<usize> {
+    let position = self
+      .records
+      .binary_search_by_key(&index, |&(i, _, _)| i)
+      .ok()?;
+    Some(self.records[position].2)
+  }
+
+  fn next_slot(&mut self) -> usize {
+    self.free.pop().unwrap_or_else(|| {
+      self.used.add_assign(1);
+      self.used.sub(1)
+    })
+  }
+}
+
+#[derive(Default)]
+struct UndoHorizon {
+  snapshots: BTreeMap<usize, usize>,
+  finished: BTreeMap<usize, usize>,
+  /// every snapshot from this commit index on can still be read.
+  retained: usize,
+  /// when each of the last retained commits became visible.
+  history: BTreeMap<usize, SystemTime>,
+}
+impl UndoHorizon {
+  fn pin(&mut self, snapshot: usize) {
+    self.snapshots.entry(snapshot).or_default().add_assign(1);
+  }
+
+  fn unpin(&mut self, snapshot: usize) {
+    if let Some(count) = self.snapshots.get_mut(&snapshot) {
+      count.sub_assign(1);
+      if (*count).eq(&0) {
+        self.snapshots.remove(&snapshot);
+      }
+    }
+  }
+
+  /// The oldest snapshot any running transaction reads at.
+  fn oldest(&self) -> usize {
+    self.snapshots.keys().next().copied().unwrap_or(usize::MAX)
+  }
+}
+
 pub struct RollbackStorage {
   cache: Mutex<LRUCache<usize, UndoLog>>,
   disk: Finder<UNDO_PAGE_SIZE>,
   config: RollbackStorageConfig,
-  cursor: Mutex<usize>,
+  ring: RwLock<UndoRing>,
+  horizon: Mutex<UndoHorizon>,
 }
 impl RollbackStorage {
   pub fn open(mut config: RollbackStorageConfig) -> Result<Self> {
@@ -132,21 +203,32 @@ impl RollbackStorage {
       write_threads: None,
     })?;
     let cache = Default::default();
-    let cursor = Default::default();
+    let ring = RwLock::new(UndoRing {
+      capacity: config.max_file_size.max(1),
+      cursor: 0,
+      records: Default::default(),
+      writers: Default::default(),
+      free: Default::default(),
+      used: 0,
+    });
 
     let storage = Self {
       cache,
       disk,
       config,
-      cursor,
+      ring,
+      horizon: Default::default(),
     };
     storage.replay()?;
     Ok(storage)
   }
 
+  /// No transaction survives a restart,
+  /// so the records on disk are only scanned to continue the index.
   fn replay(&self) -> Result<()> {
-    let mut cursor = 0;
-    for index in 0..self.config.max_file_size {
+    let mut ring = self.ring.wl();
+    let len = self.disk.len()?;
+    for index in 0..len {
       let log: UndoLog = match self.disk.read(index) {
         Ok(page) => match page.deserialize() {
           Ok(log) => log,
@@ -154,84 +236,242 @@ impl RollbackStorage {
         },
         Err(_) => break,
       };
-      let before = cursor;
-      cursor = log.index.add(1).rem_euclid(usize::MAX);
-      if index.eq(&0) {
-        continue;
-      }
-      if before.ne(&log.index) {
-        break;
-      }
+      ring.cursor = ring.cursor.max(log.index);
     }
-    *self.cursor.l() = cursor;
 
     Ok(())
   }
 
-  pub fn get(&self, commit_index: usize, undo_index: usize) -> Result<Page> {
-    let mut current = undo_index;
-    loop {
-      let mut cache = self.cache.l();
-      if let Some(log) = cache.get(&current) {
-        if commit_index.ge(&log.commit_index) {
-          return Ok(log.data.copy());
-        }
-        match log.undo_index {
-          Some(i) => {
-            current = i;
-            continue;
-          }
-          None => return Err(Error::NotFound),
-        }
-      }
+  /// Keeps the versions visible at the snapshot until it is unpinned.
+  pub fn pin<F>(&self, snapshot: F) -> usize
+  where
+    F: FnOnce() -> usize,
+  {
+    // nothing committed is reclaimed until the snapshot is known.
+    self.horizon.l().pin(0);
+    let snapshot = snapshot();
+    let mut horizon = self.horizon.l();
+    horizon.pin(snapshot);
+    horizon.unpin(0);
+    snapshot
+  }
 
-      let log: UndoLog = self
-        .disk
-        .read(undo_index.rem_euclid(self.config.max_file_size))?
-        .deserialize()?;
-      if log.index.ne(&undo_index) {
-        return Err(Error::NotFound);
+  /// Pins a past snapshot, which fails once its versions may be reclaimed.
+  pub fn pin_at(&self, snapshot: usize) -> Result<usize> {
+    let mut horizon = self.horizon.l();
+    if snapshot.lt(&horizon.retained) {
+      return Err(Error::SnapshotTooOld);
+    }
+    horizon.pin(snapshot);
+    Ok(snapshot)
+  }
+
+  pub fn unpin(&self, snapshot: usize) {
+    self.horizon.l().unpin(snapshot)
+  }
+
+  /// The oldest pinned snapshot, usize::MAX when there is none.
+  pub fn oldest(&self) -> usize {
+    self.horizon.l().oldest()
+  }
+
+  /// Hands the oldest pinned snapshot to `f`, which drops what no snapshot
+  /// from there on can read and returns the commit index to retain from.
+  /// Nothing can be pinned in between, as the horizon stays locked.
+  pub fn retain_with<T, F>(&self, f: F) -> T
+  where
+    F: FnOnce(usize) -> (T, usize),
+  {
+    let mut horizon = self.horizon.l();
+    let (value, retained) = f(horizon.oldest());
+    if retained.gt(&horizon.retained) {
+      horizon.retained = retained;
+      horizon.history = horizon.history.split_off(&retained);
+    }
+    value
+  }
+
+  /// The records of the transaction can be reclaimed
+  /// once every snapshot includes the commit, 0 when it was aborted.
+  pub fn finish(&self, tx_id: usize, commit_index: usize) {
+    let ring = self.ring.rl();
+    let mut horizon = self.horizon.l();
+    if commit_index.ne(&0) {
+      horizon
+        .history
+        .entry(commit_index)
+        .or_insert_with(SystemTime::now);
+      if horizon.history.len().gt(&MAX_HISTORY) {
+        horizon.history.pop_first();
       }
+    }
+    if ring.writers.contains_key(&tx_id) {
+      horizon.finished.insert(tx_id, commit_index);
+    }
+  }
+
+  /// Nothing from before a restart is tracked, so the history starts over.
+  pub fn start_history(&self, commit_index: usize) {
+    let mut horizon = self.horizon.l();
+    horizon.retained = horizon.retained.max(commit_index);
+    horizon.history.clear();
+    horizon.history.insert(commit_index, SystemTime::now());
+  }
 
-      cache.insert(undo_index, log.clone());
-      if cache.len().ge(&self.config.max_cache_size) {
-        cache.pop_old();
+  /// The latest retained commit visible at the time.
+  pub fn commit_index_at(&self, time: SystemTime) -> Option<usize> {
+    let horizon = self.horizon.l();
+    horizon
+      .history
+      .iter()
+      .rev()
+      .find(|(_, t)| t.le(&&time))
+      .map(|(&commit_index, _)| commit_index)
+  }
+
+  /// Frees the oldest records of the full ring as long as no snapshot needs them.
+  /// Records below the horizon are kept until the ring is full,
+  /// so past commits stay readable for as long as the capacity allows.
+  /// A record a pinned snapshot still reads stops it like a running writer,
+  /// and the file grows past the capacity instead.
+  fn reclaim(&self, ring: &mut UndoRing) {
+    let mut horizon = self.horizon.l();
+    let oldest = horizon.oldest();
+    while ring.records.len().ge(&ring.capacity) {
+      let writer = match ring.records.front() {
+        Some(&(_, writer, _)) => writer,
+        None => break,
+      };
+      let commit_index = match horizon.finished.get(&writer) {
+        Some(&commit_index) => commit_index,
+        None => {
+          logger::error(format!(
+            "undo log over {} records, tx {} is still running",
+            ring.capacity, writer
+          ));
+          break;
+        }
+      };
+      if commit_index.gt(&oldest) {
+        logger::error(format!(
+          "undo log over {} records, snapshot {} still reads them",
+          ring.capacity, oldest
+        ));
+        break;
       }
-      if commit_index.ge(&log.commit_index) {
-        return Ok(log.data);
+      if commit_index.gt(&horizon.retained) {
+        horizon.retained = commit_index;
+        horizon.history = horizon.history.split_off(&commit_index);
       }
+      if let Some((_, _, slot)) = ring.records.pop_front() {
+        ring.free.push(slot);
+      }
+      if let Some(count) = ring.writers.get_mut(&writer) {
+        count.sub_assign(1);
+        if (*count).eq(&0) {
+          ring.writers.remove(&writer);
+          horizon.finished.remove(&writer);
+        }
+      }
+    }
+  }
 
+  pub fn get(&self, tx_id: usize, snapshot: usize, undo_index: usize) -> Result<Page> {
+    let mut current = undo_index;
+    loop {
+      let log = self.read(current)?;
+      if log.is_visible(tx_id, snapshot) {
+        return Ok(log.data);
+      }
       match log.undo_index {
-        Some(i) => {
-          current = i;
-          continue;
-        }
+        Some(i) => current = i,
         None => return Err(Error::NotFound),
       }
     }
   }
 
-  pub fn append(&self, data: DataBlock) -> Result<usize> {
-    let index = {
-      let mut c = self.cursor.l();
-      let index = c.add(1).rem_euclid(usize::MAX);
-      *c = index;
-      index
+  pub fn restore(&self, tx_id: usize, undo_index: usize) -> Result<Option<DataBlock>> {
+    let mut current = undo_index;
+    loop {
+      let log = self.read(current)?;
+      if log.tx_id.ne(&tx_id) {
+        return Ok(Some(DataBlock::new(
+          log.commit_index,
+          log.tx_id,
+          log.undo_index,
+          log.data,
+        )));
+      }
+      match log.undo_index {
+        Some(i) => current = i,
+        None => return Ok(None),
+      }
+    }
+  }
+
+  /// The block the undo record was taken from.
+  pub fn get_block(&self, undo_index: usize) -> Result<DataBlock> {
+    let log = self.read(undo_index)?;
+    Ok(DataBlock::new(
+      log.commit_index,
+      log.tx_id,
+      log.undo_index,
+      log.data,
+    ))
+  }
+
+  fn read(&self, undo_index: usize) -> Result<UndoLog> {
+    let ring = self.ring.rl();
+    let mut cache = self.cache.l();
+    if let Some(log) = cache.get(&undo_index) {
+      return Ok(log.clone());
+    }
+
+    let slot = match ring.slot(undo_index) {
+      Some(slot) => slot,
+      None => return Err(Error::SnapshotTooOld),
     };
+    let log: UndoLog = self.disk.read(slot)?.deserialize()?;
+    if log.index.ne(&undo_index) {
+      // the slot was reused by a newer record, or never written since a restart.
+      return Err(Error::SnapshotTooOld);
+    }
+
+    cache.insert(undo_index, log.clone());
+    if cache.len().ge(&self.config.max_cache_size) {
+      cache.pop_old();
+    }
+    Ok(log)
+  }
+
+  pub fn append(&self, tx_id: usize, data: DataBlock) -> Result<usize> {
+    let (index, slot) = {
+      let mut ring = self.ring.wl();
+      self.reclaim(&mut ring);
+      let index = ring.cursor.add(1).rem_euclid(usize::MAX);
+      let slot = ring.next_slot();
+      ring.cursor = index;
+      ring.records.push_back((index, tx_id, slot));
+      ring.writers.entry(tx_id).or_default().add_assign(1);
+      (index, slot)
+    };
+    // the record belongs to a running transaction, so the slot stays its own.
     self
       .disk
-      .write(index, UndoLog::from_data(index, data).serialize()?)?;
+      .write(slot, UndoLog::from_data(index, data).serialize()?)?;
     Ok(index)
   }
 
   pub fn commit(&self, undo_index: usize, commit: &CommitInfo) -> Result<()> {
+    let ring = self.ring.rl();
     let mut current = undo_index;
     loop {
+      let slot = ring.slot(current).ok_or(Error::NotFound)?;
       let mut cache = self.cache.l();
       if let Some(log) = cache.get_mut(&current) {
         if commit.tx_id.eq(&log.tx_id) {
           log.commit_index = commit.commit_index;
-          return self.disk.write(current, log.serialize()?);
+          return self.disk.write(slot, log.serialize()?);
         }
 
         match log.undo_index {
@@ -243,19 +483,16 @@ impl RollbackStorage {
         }
       }
 
-      let mut log: UndoLog = self
-        .disk
-        .read(undo_index.rem_euclid(self.config.max_file_size))?
-        .deserialize()?;
-      if log.index.ne(&undo_index) {
+      let mut log: UndoLog = self.disk.read(slot)?.deserialize()?;
+      if log.index.ne(&current) {
         return Err(Error::NotFound);
       }
 
       if commit.tx_id.eq(&log.tx_id) {
         log.commit_index = commit.commit_index;
-        self.disk.write(current, log.serialize()?)?;
+        self.disk.write(slot, log.serialize()?)?;
 
-        cache.insert(undo_index, log.clone());
+        cache.insert(current, log.clone());
         if cache.len().ge(&self.config.max_cache_size) {
           cache.pop_old();
         }
@@ -277,3 +514,143 @@ impl RollbackStorage {
     self.disk.close();
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    fs,
+    ops::{Add, Mul},
+    path::PathBuf,
+    time::{Duration, SystemTime},
+  };
+
+  use super::{RollbackStorage, RollbackStorageConfig, MAX_HISTORY, UNDO_PAGE_SIZE};
+  use crate::{buffer::DataBlock, Error, Page, ShortenedMutex, ShortenedRwLock};
+
+  fn open(name: &str, records: usize) -> (RollbackStorage, PathBuf) {
+    let path = std::env::temp_dir().join(format!("lfkv-{}-{}", name, std::process::id()));
+    fs::remove_file(&path).ok();
+    let storage = RollbackStorage::open(RollbackStorageConfig {
+      fsync_delay: Duration::from_millis(1),
+      fsync_count: 10,
+      max_cache_size: UNDO_PAGE_SIZE,
+      max_file_size: UNDO_PAGE_SIZE.mul(records),
+      path: path.clone(),
+    })
+    .unwrap();
+    (storage, path)
+  }
+
+  fn block(tx_id: usize, byte: u8) -> DataBlock {
+    let mut data = Page::new();
+    data.writer().write(&[byte]).unwrap();
+    DataBlock::new(1, tx_id, None, data)
+  }
+
+  fn read(storage: &RollbackStorage, index: usize) -> Result<u8, Error> {
+    storage.get_block(index)?.data.scanner().read()
+  }
+
+  #[test]
+  fn _1() {
+    let (storage, path) = open("undo-1", 4);
+    assert_eq!(storage.pin(|| 5), 5);
+    assert_eq!(storage.pin(|| 5), 5);
+    storage.unpin(5);
+    assert_eq!(storage.horizon.l().oldest(), 5);
+
+    let a = storage.append(1, block(1, 1)).unwrap();
+    let b = storage.append(1, block(1, 2)).unwrap();
+    storage.finish(1, 3);
+    let c = storage.append(2, block(2, 3)).unwrap();
+    storage.append(2, block(2, 4)).unwrap();
+    storage.finish(2, 7);
+
+    // versions replaced at or before the pinned snapshot go first.
+    storage.append(3, block(3, 5)).unwrap();
+    assert!(matches!(read(&storage, a), Err(Error::SnapshotTooOld)));
+    assert_eq!(read(&storage, b).unwrap(), 2);
+    assert!(matches!(storage.pin_at(2), Err(Error::SnapshotTooOld)));
+    storage.unpin(storage.pin_at(5).unwrap());
+
+    // versions the pinned snapshot reads are kept past the capacity.
+    storage.append(3, block(3, 6)).unwrap();
+    storage.append(3, block(3, 7)).unwrap();
+    assert_eq!(read(&storage, c).unwrap(), 3);
+    storage.unpin(storage.pin_at(5).unwrap());
+    assert_eq!(storage.disk.len().unwrap(), 5);
+
+    storage.unpin(5);
+    assert_eq!(storage.horizon.l().oldest(), usize::MAX);
+    storage.append(4, block(4, 8)).unwrap();
+    assert!(matches!(read(&storage, c), Err(Error::SnapshotTooOld)));
+    assert_eq!(storage.disk.len().unwrap(), 5);
+    storage.destroy();
+    fs::remove_file(path).ok();
+  }
+
+  #[test]
+  fn _2() {
+    let (storage, path) = open("undo-2", 2);
+    let indexes: Vec<usize> = (0..3)
+      .map(|i| storage.append(1, block(1, i)).unwrap())
+      .collect();
+    // records of a running transaction are never overwritten.
+    for (i, &index) in indexes.iter().enumerate() {
+      assert_eq!(read(&storage, index).unwrap(), i as u8);
+    }
+
+    storage.finish(1, 2);
+    let index = storage.append(2, block(2, 9)).unwrap();
+    assert_eq!(read(&storage, index).unwrap(), 9);
+    assert!(matches!(
+      read(&storage, indexes[0]),
+      Err(Error::SnapshotTooOld)
+    ));
+    assert_eq!(storage.disk.len().unwrap(), 3);
+    storage.destroy();
+    fs::remove_file(path).ok();
+  }
+
+  #[test]
+  fn _3() {
+    let (storage, path) = open("undo-3", 4);
+    let index = storage.append(1, block(1, 1)).unwrap();
+    let other = storage.append(1, block(1, 2)).unwrap();
+    let slot = storage.ring.rl().slot(index).unwrap();
+    let mut page = Page::<UNDO_PAGE_SIZE>::new();
+    let mut wt = page.writer();
+    wt.write(&index.to_be_bytes()).unwrap();
+    wt.write(&[0; 16]).unwrap();
+    wt.write(&[7]).unwrap();
+    storage.disk.write(slot, page).unwrap();
+
+    let err = read(&storage, index).unwrap_err();
+    assert!(matches!(err, Error::Invalid));
+    assert!(!err.is_retryable());
+    assert_eq!(read(&storage, other).unwrap(), 2);
+    assert!(matches!(
+      read(&storage, other.add(1)),
+      Err(Error::SnapshotTooOld)
+    ));
+    storage.destroy();
+    fs::remove_file(path).ok();
+  }
+
+  #[test]
+  fn _4() {
+    let (storage, path) = open("undo-4", 4);
+    let start = SystemTime::now();
+    for commit_index in 1..=MAX_HISTORY.add(10) {
+      storage.finish(commit_index, commit_index);
+    }
+    assert_eq!(storage.horizon.l().history.len(), MAX_HISTORY);
+    assert_eq!(
+      storage.commit_index_at(SystemTime::now()),
+      Some(MAX_HISTORY.add(10))
+    );
+    assert_eq!(storage.commit_index_at(start), None);
+    storage.destroy();
+    fs::remove_file(path).ok();
+  }
+}
@@ -1,5 +1,5 @@
 use std::{
-  ops::Add,
+  ops::{Add, Bound, RangeBounds, Sub},
   sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
@@ -11,44 +11,85 @@ use crate::{
   disk::FreeList,
   logger,
   wal::WriteAheadLog,
-  Error, Page, Result, Serializable,
+  Error, Result, Serializable,
 };
 
 use super::{
-  entry::MIN_NODE_LEN, CursorEntry, CursorWriter, InternalNode, LeafNode, TreeHeader,
-  HEADER_INDEX, MAX_NODE_LEN,
+  CursorEntry, CursorIterator, CursorWriter, Defragmentation, InternalNode,
+  IsolationLevel, KeyLocks, LeafNode, LeafValue, Savepoint, SerializableTracker,
+  TransactionOptions, TreeHeader, DEFAULT_KEYSPACE, HEADER_INDEX, LENDABLE_NODE_SIZE,
+  MAX_INLINE_VALUE_SIZE, MAX_NODE_SIZE, MIN_NODE_SIZE,
 };
 
+/// The new page and separator of a node that split.
+type Split = Option<(usize, Vec<u8>)>;
+
+/// What a delete left in a node for its parent.
+enum Deleted {
+  NotFound,
+  Done,
+  /// the node fell below the minimum and is written by its parent,
+  /// which lends it entries or merges it.
+  Underflow(CursorEntry),
+  /// a separator borrowed from below was longer than the one it replaced,
+  /// and the node split like it would on insert.
+  Split(usize, Vec<u8>),
+}
+
 pub struct Cursor {
   committed: Arc<AtomicBool>,
   writer: CursorWriter,
+  tracker: Arc<SerializableTracker>,
+  locks: Arc<KeyLocks>,
+  max_key_size: usize,
 }
 impl Cursor {
   pub fn new(
     freelist: Arc<FreeList<BLOCK_SIZE>>,
     wal: Arc<WriteAheadLog>,
     buffer: Arc<BufferPool>,
+    tracker: Arc<SerializableTracker>,
+    locks: Arc<KeyLocks>,
+    max_key_size: usize,
+    options: TransactionOptions,
   ) -> Result<Self> {
-    let (tx_id, last_commit_index) = wal.new_transaction()?;
+    let (tx_id, _) = wal.new_transaction()?;
+    let pin = || buffer.pin_snapshot(|| wal.last_commit_index());
+    let last_commit_index = match options.isolation {
+      IsolationLevel::Serializable => tracker.begin(tx_id, pin),
+      _ => pin(),
+    };
     logger::info(format!(
       "cursor id {} and lsn {} init",
       tx_id, last_commit_index
     ));
     Ok(Self {
       committed: Arc::new(AtomicBool::new(false)),
-      writer: CursorWriter::new(tx_id, last_commit_index, wal, buffer, freelist),
+      writer: CursorWriter::new(
+        tx_id,
+        last_commit_index,
+        options.isolation,
+        wal,
+        buffer,
+        freelist,
+      ),
+      tracker,
+      locks,
+      max_key_size,
     })
   }
 
   pub fn initialize(&self) -> Result {
     if let Err(Error::NotFound) = self
       .writer
-      .get(HEADER_INDEX)?
-      .deserialize::<TreeHeader, Error>()
+      .get(HEADER_INDEX)
+      .and_then(|page| page.deserialize::<TreeHeader, Error>())
     {
       logger::info("there are no tree header and will be initialized");
       let header = TreeHeader::initial_state();
-      let root = header.get_root();
+      let root = header.get_root(DEFAULT_KEYSPACE)?;
+      self.writer.reserve(HEADER_INDEX);
+      self.writer.reserve(root);
       self.writer.update(HEADER_INDEX, header.serialize()?)?;
       self
         .writer
@@ -57,308 +98,621 @@ impl Cursor {
     Ok(())
   }
 
-  pub fn get(&self, key: &Vec<u8>) -> Result<Vec<u8>> {
+  pub fn keyspace(&self, name: &str) -> Result<Keyspace<'_>> {
     if self.committed.load(Ordering::SeqCst) {
       return Err(Error::TransactionClosed);
     }
+    self.writer.refresh();
+
+    self.get_root(name)?;
+    Ok(Keyspace {
+      cursor: self,
+      name: name.to_string(),
+    })
+  }
+
+  /// The keyspace is added to the header when the transaction commits.
+  pub fn create_keyspace(&self, name: &str) -> Result {
+    if self.committed.load(Ordering::SeqCst) {
+      return Err(Error::TransactionClosed);
+    }
+    self.writer.refresh();
+
+    let header: TreeHeader = self.writer.get_latest(HEADER_INDEX)?.deserialize()?;
+    header.check_new_root(name)?;
+    let mut pending = self.writer.pending();
+    if pending.has_keyspace(name) {
+      return Err(Error::KeyspaceAlreadyExists);
+    }
+    pending.create_keyspace(name);
+    Ok(())
+  }
+
+  pub fn get(&self, key: &Vec<u8>) -> Result<Vec<u8>> {
+    self.get_in(DEFAULT_KEYSPACE, key)
+  }
+
+  /// Locks the key until commit or abort and reads its latest value.
+  pub fn get_for_update(&self, key: &Vec<u8>) -> Result<Vec<u8>> {
+    self.get_for_update_in(DEFAULT_KEYSPACE, key)
+  }
 
-    Ok(self.writer.get(self.get_index(key)?)?.into())
+  pub fn range<R>(&self, range: R) -> Result<CursorIterator<'_>>
+  where
+    R: RangeBounds<Vec<u8>>,
+  {
+    self.range_in(DEFAULT_KEYSPACE, range)
+  }
+
+  pub fn scan_prefix(&self, prefix: &[u8]) -> Result<CursorIterator<'_>> {
+    self.scan_prefix_in(DEFAULT_KEYSPACE, prefix)
   }
 
   pub fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result {
+    self.insert_in(DEFAULT_KEYSPACE, key, value)
+  }
+
+  pub fn delete(&self, key: &Vec<u8>) -> Result<bool> {
+    self.delete_in(DEFAULT_KEYSPACE, key)
+  }
+
+  fn get_in(&self, keyspace: &str, key: &Vec<u8>) -> Result<Vec<u8>> {
     if self.committed.load(Ordering::SeqCst) {
       return Err(Error::TransactionClosed);
     }
+    self.writer.refresh();
 
-    let mut header: TreeHeader = self.writer.get(HEADER_INDEX)?.deserialize()?;
-    let (_, evicted, inserted) = self._insert(header.get_root(), key, value.into())?;
-    if !inserted {
-      return Ok(());
+    let point = (Bound::Included(key.clone()), Bound::Included(key.clone()));
+    self.track_read(keyspace, point)?;
+    self.get_value(keyspace, key)?.ok_or(Error::NotFound)
+  }
+
+  fn get_for_update_in(&self, keyspace: &str, key: &Vec<u8>) -> Result<Vec<u8>> {
+    if self.committed.load(Ordering::SeqCst) {
+      return Err(Error::TransactionClosed);
     }
+    self.lock_key(keyspace, key)?;
 
-    let (ni, s) = match evicted {
-      Some((ni, s)) => (ni, s),
-      None => return Ok(()),
-    };
+    let point = (Bound::Included(key.clone()), Bound::Included(key.clone()));
+    self.track_read(keyspace, point)?;
+    self.get_value(keyspace, key)?.ok_or(Error::NotFound)
+  }
 
-    let new_root = CursorEntry::Internal(InternalNode {
-      keys: vec![s],
-      children: vec![header.get_root(), ni],
-    });
+  fn range_in<R>(&self, keyspace: &str, range: R) -> Result<CursorIterator<'_>>
+  where
+    R: RangeBounds<Vec<u8>>,
+  {
+    if self.committed.load(Ordering::SeqCst) {
+      return Err(Error::TransactionClosed);
+    }
+    self.writer.refresh();
 
-    let nri = self.writer.insert(new_root.serialize()?)?;
-    header.set_root(nri);
-    self.writer.update(HEADER_INDEX, header.serialize()?)?;
+    let iter = CursorIterator::new(
+      self.writer.reader(),
+      self.get_root(keyspace)?,
+      range.start_bound().cloned(),
+      range.end_bound().cloned(),
+    );
+    self.track_read(keyspace, iter.bounds())?;
+    let pending = self.writer.pending().range(keyspace, &iter.bounds());
+    Ok(iter.with_pending(pending))
+  }
 
-    Ok(())
+  fn scan_prefix_in(&self, keyspace: &str, prefix: &[u8]) -> Result<CursorIterator<'_>> {
+    if self.committed.load(Ordering::SeqCst) {
+      return Err(Error::TransactionClosed);
+    }
+    self.writer.refresh();
+
+    let iter =
+      CursorIterator::prefix(self.writer.reader(), self.get_root(keyspace)?, prefix);
+    self.track_read(keyspace, iter.bounds())?;
+    let pending = self.writer.pending().range(keyspace, &iter.bounds());
+    Ok(iter.with_pending(pending))
   }
 
-  fn _insert(
-    &self,
-    current: usize,
-    key: Vec<u8>,
-    page: Page,
-  ) -> Result<(Option<Vec<u8>>, Option<(usize, Vec<u8>)>, bool)> {
-    let entry: CursorEntry = self.writer.get(current)?.deserialize()?;
-    match entry {
-      CursorEntry::Internal(mut node) => {
-        let (i, ci) = match node.keys.binary_search(&key) {
-          Ok(i) => (i, node.children[i + 1]),
-          Err(i) => (i, node.children[i]),
-        };
+  fn insert_in(&self, keyspace: &str, key: Vec<u8>, value: Vec<u8>) -> Result {
+    if self.committed.load(Ordering::SeqCst) {
+      return Err(Error::TransactionClosed);
+    }
+    self.writer.refresh();
+    if key.len().gt(&self.max_key_size) {
+      return Err(Error::KeyTooLarge);
+    }
+    self.get_root(keyspace)?;
+    self.lock_key(keyspace, &key)?;
+    self.track_write(keyspace, &key)?;
 
-        let (fk, evicted, inserted) = self._insert(ci, key, page)?;
-        if !inserted {
-          return Ok((None, None, false));
-        }
+    self.writer.pending().insert(keyspace, key, Some(value));
+    Ok(())
+  }
 
-        if let Some(k) = fk {
-          node.keys[i] = k;
-        }
+  fn delete_in(&self, keyspace: &str, key: &Vec<u8>) -> Result<bool> {
+    if self.committed.load(Ordering::SeqCst) {
+      return Err(Error::TransactionClosed);
+    }
+    self.writer.refresh();
+    self.get_root(keyspace)?;
+    self.lock_key(keyspace, key)?;
+    self.track_write(keyspace, key)?;
 
-        if let Some((en, ek)) = evicted {
-          node.keys.insert(i, ek);
-          node.children.insert(i + 1, en);
-          if node.len().le(&MAX_NODE_LEN) {
-            self.writer.update(current, node.serialize()?)?;
-            return Ok((None, None, true));
-          }
+    let found = self.get_value(keyspace, key)?.is_some();
+    self.writer.pending().insert(keyspace, key.clone(), None);
+    Ok(found)
+  }
 
-          let (n, s) = node.split();
-          let ni = self.writer.insert(n.serialize()?)?;
-          self.writer.update(current, node.serialize()?)?;
-          return Ok((None, Some((ni, s)), true));
-        }
+  pub fn defragment(&self) -> Result<usize> {
+    if self.committed.load(Ordering::SeqCst) {
+      return Err(Error::TransactionClosed);
+    }
 
-        return Ok((None, None, true));
-      }
-      CursorEntry::Leaf(mut node) => {
-        match node.keys.binary_search_by(|(k, _)| k.cmp(&key)) {
-          Ok(i) => {
-            self.writer.update(node.keys[i].1, page)?;
-            return Ok((None, None, false));
-          }
-          Err(i) => {
-            let pi = self.writer.insert(page)?;
-            node.keys.insert(i, (key.clone(), pi));
-            let fk = (i == 0).then(|| key);
+    Defragmentation::new(&self.writer).run()
+  }
 
-            if node.len().le(&MAX_NODE_LEN) {
-              self.writer.update(current, node.serialize()?)?;
-              return Ok((fk, None, true));
-            }
+  pub fn savepoint(&self) -> Result<Savepoint> {
+    if self.committed.load(Ordering::SeqCst) {
+      return Err(Error::TransactionClosed);
+    }
 
-            let (n, s) = node.split(current);
-            let ni = self.writer.insert(n.serialize()?)?;
-            node.set_next(ni);
-            self.writer.update(current, node.serialize()?)?;
-            return Ok((fk, Some((ni, s)), true));
-          }
-        };
-      }
+    Ok(self.writer.savepoint())
+  }
+
+  /// Undoes the writes made after the savepoint and keeps the earlier ones.
+  pub fn rollback_to(&self, savepoint: &Savepoint) -> Result {
+    if self.committed.load(Ordering::SeqCst) {
+      return Err(Error::TransactionClosed);
     }
+
+    logger::info(format!(
+      "cursor id {} rollback to savepoint",
+      self.writer.get_id()
+    ));
+    self.writer.rollback_to(savepoint)
   }
 
-  pub fn delete(&self, key: &Vec<u8>) -> Result<bool> {
+  /// Applies the pending writes to the tree, then commits them.
+  pub fn commit(&self) -> Result {
     if self.committed.load(Ordering::SeqCst) {
       return Err(Error::TransactionClosed);
     }
 
-    let mut header: TreeHeader = self.writer.get(HEADER_INDEX)?.deserialize()?;
-    let root = header.get_root();
-    let (e, _) = match self._delete(key, root)? {
-      None => return Ok(false),
-      Some(v) => v,
+    logger::info(format!("cursor id {} commit start", self.writer.get_id()));
+    self.tracker.prepare(self.writer.get_id())?;
+    self.apply()?;
+    let commit_index = self.writer.commit()?;
+    self.committed.store(true, Ordering::SeqCst);
+    let written = self
+      .writer
+      .pending()
+      .entries()
+      .into_iter()
+      .map(|(keyspace, key, _)| (keyspace, key))
+      .collect();
+    self.locks.commit(
+      self.writer.get_id(),
+      commit_index,
+      written,
+      self.writer.oldest_snapshot(),
+    );
+    self.tracker.commit(self.writer.get_id(), commit_index);
+    Ok(())
+  }
+
+  pub fn abort(&self) -> Result {
+    if self.committed.swap(true, Ordering::SeqCst) {
+      return Err(Error::TransactionClosed);
+    }
+
+    logger::info(format!("cursor id {} abort start", self.writer.get_id()));
+    self.tracker.abort(self.writer.get_id());
+    let result = self.writer.abort();
+    self.locks.release_all(self.writer.get_id());
+    result
+  }
+}
+impl Cursor {
+  /// The root of the keyspace as of the snapshot,
+  /// None for one created by this transaction.
+  fn get_root(&self, keyspace: &str) -> Result<Option<usize>> {
+    match self.writer.reader().get_root(keyspace) {
+      Ok(root) => Ok(Some(root)),
+      Err(Error::KeyspaceNotFound) if self.writer.pending().has_keyspace(keyspace) => {
+        Ok(None)
+      }
+      Err(err) => Err(err),
+    }
+  }
+
+  /// The value this transaction sees, its own writes first.
+  fn get_value(&self, keyspace: &str, key: &Vec<u8>) -> Result<Option<Vec<u8>>> {
+    if let Some(value) = self.writer.pending().get(keyspace, key) {
+      return Ok(value);
+    }
+    if self.get_root(keyspace)?.is_none() {
+      return Ok(None);
+    }
+    match self.writer.reader().get_index(keyspace, key) {
+      Ok(value) => self.writer.get_leaf_value(value).map(Some),
+      Err(Error::NotFound) => Ok(None),
+      Err(err) => Err(err),
+    }
+  }
+
+  /// Read committed moves to the latest commit once the key is locked,
+  /// other levels fail if the key was committed after their snapshot.
+  fn lock_key(&self, keyspace: &str, key: &[u8]) -> Result {
+    self.locks.acquire(self.writer.get_id(), keyspace, key)?;
+    if let IsolationLevel::ReadCommitted = self.writer.get_isolation() {
+      self.writer.refresh();
+      return Ok(());
+    }
+    let snapshot = self.writer.reader().get_snapshot();
+    self.locks.check_version(keyspace, key, snapshot)
+  }
+
+  fn track_read(
+    &self,
+    keyspace: &str,
+    range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+  ) -> Result {
+    if let IsolationLevel::Serializable = self.writer.get_isolation() {
+      return self.tracker.read(self.writer.get_id(), keyspace, range);
+    }
+    Ok(())
+  }
+
+  fn track_write(&self, keyspace: &str, key: &Vec<u8>) -> Result {
+    if let IsolationLevel::Serializable = self.writer.get_isolation() {
+      return self.tracker.write(self.writer.get_id(), keyspace, key);
+    }
+    Ok(())
+  }
+
+  /// Writes the new keyspaces and keys into the tree, one at a time,
+  /// keeping only the pages they changed locked.
+  fn apply(&self) -> Result {
+    let (keyspaces, writes) = {
+      let pending = self.writer.pending();
+      (pending.keyspaces(), pending.entries())
     };
-    match &e {
-      CursorEntry::Leaf(_) => {
-        self.writer.update(root, e.serialize()?)?;
-        Ok(true)
+    if keyspaces.is_empty() && writes.is_empty() {
+      return Ok(());
+    }
+
+    self.writer.apply(|| {
+      for name in &keyspaces {
+        self.add_root(name)?;
+        self.writer.unlock_unchanged();
       }
-      CursorEntry::Internal(node) => {
-        if node.len().gt(&0) {
-          self.writer.update(root, e.serialize()?)?;
-          return Ok(true);
-        }
-        header.set_root(node.children[0]);
-        self.writer.update(HEADER_INDEX, header.serialize()?)?;
-        self.writer.release(root)?;
-        Ok(true)
+      for (keyspace, key, value) in &writes {
+        match value {
+          Some(value) => self.insert_root(keyspace, key, value.clone())?,
+          None => self.delete_root(keyspace, key)?,
+        };
+        self.writer.unlock_unchanged();
+      }
+      Ok(())
+    })
+  }
+
+  fn add_root(&self, name: &str) -> Result {
+    let mut header: TreeHeader =
+      self.writer.get_for_update(HEADER_INDEX)?.deserialize()?;
+    header.check_new_root(name)?;
+    let root = self
+      .writer
+      .insert(CursorEntry::Leaf(LeafNode::empty()).serialize()?)?;
+    header.add_root(name, root)?;
+    self.writer.update(HEADER_INDEX, header.serialize()?)
+  }
+
+  /// Roots stay where they are, so the header is only read. It is read again
+  /// once the root is locked, in case defragmentation moved the root meanwhile.
+  fn lock_root(&self, keyspace: &str) -> Result<usize> {
+    loop {
+      let header: TreeHeader = self.writer.get_latest(HEADER_INDEX)?.deserialize()?;
+      let root = header.get_root(keyspace)?;
+      self.writer.lock(root)?;
+      let header: TreeHeader = self.writer.get_latest(HEADER_INDEX)?.deserialize()?;
+      if header.get_root(keyspace)?.eq(&root) {
+        return Ok(root);
       }
+      self.writer.unlock(root);
     }
   }
 
-  fn _delete(
+  /// Latch crabbing: once a node can take the change coming from below
+  /// without splitting or merging, the nodes above it stay as they are
+  /// and their locks are released.
+  fn crab(&self, safe: bool, ancestors: &mut Vec<usize>, current: usize) {
+    if safe {
+      for index in ancestors.drain(..) {
+        self.writer.unlock(index);
+      }
+    }
+    ancestors.push(current);
+  }
+
+  fn insert_root(&self, keyspace: &str, key: &Vec<u8>, value: Vec<u8>) -> Result {
+    let root = self.lock_root(keyspace)?;
+    match self._insert(root, key, value, &mut vec![])? {
+      Some((ni, s)) => self.split_root(root, ni, s),
+      None => Ok(()),
+    }
+  }
+
+  fn split_root(&self, root: usize, ni: usize, s: Vec<u8>) -> Result {
+    // the left half moves out of the root page, which the header points to.
+    let entry: CursorEntry = self.writer.get_for_update(root)?.deserialize()?;
+    let left = self.writer.insert(entry.serialize()?)?;
+    if let CursorEntry::Leaf(_) = entry {
+      self.link_prev(Some(ni), left)?;
+    }
+    let new_root = CursorEntry::Internal(InternalNode {
+      keys: vec![s],
+      children: vec![left, ni],
+    });
+    self.writer.update(root, new_root.serialize()?)
+  }
+
+  fn _insert(
     &self,
-    key: &Vec<u8>,
     current: usize,
-  ) -> Result<Option<(CursorEntry, Vec<u8>)>> {
-    let entry: CursorEntry = self.writer.get(current)?.deserialize()?;
+    key: &Vec<u8>,
+    value: Vec<u8>,
+    ancestors: &mut Vec<usize>,
+  ) -> Result<Split> {
+    let entry: CursorEntry = self.writer.get_for_update(current)?.deserialize()?;
+    self.crab(entry.can_grow(), ancestors, current);
     match entry {
       CursorEntry::Internal(mut node) => {
-        let ((ii, ci), left, right) = node.find_family(key);
-        let (child_entry, successor) = match self._delete(key, ci)? {
+        let (c, _, _) = node.find_family(key);
+        let (en, ek) = match self._insert(node.children[c], key, value, ancestors)? {
+          Some(split) => split,
           None => return Ok(None),
-          Some(c) => c,
         };
-        match child_entry {
-          CursorEntry::Internal(mut child) => {
-            if node.keys[ii].eq(key) {
-              node.keys[ii] = successor.clone();
-            }
-            if child.len().ge(&MIN_NODE_LEN) {
-              self.writer.update(ci, child.serialize()?)?;
-              return Ok(Some((CursorEntry::Internal(node), successor)));
-            };
-
-            if let Some(left) = left {
-              let mut left_entry: CursorEntry = self.writer.get(left)?.deserialize()?;
-              if left_entry.as_internal().len().gt(&MIN_NODE_LEN) {
-                let (k, p) = left_entry.as_internal().pop_back().unwrap();
-                node.push_front(k.clone(), p);
-                node.keys[ii] = k;
-                self.writer.update(left, left_entry.serialize()?)?;
-                self.writer.update(ci, child.serialize()?)?;
-                return Ok(Some((CursorEntry::Internal(node), successor)));
-              }
-            }
+        node.keys.insert(c, ek);
+        node.children.insert(c.add(1), en);
+        if node.byte_len().le(&MAX_NODE_SIZE) {
+          self.writer.update(current, node.serialize()?)?;
+          return Ok(None);
+        }
 
-            if let Some(right) = right {
-              let mut right_entry: CursorEntry = self.writer.get(right)?.deserialize()?;
-              if right_entry.as_internal().len().gt(&MIN_NODE_LEN) {
-                let (k, p) = right_entry.as_internal().pop_front().unwrap();
-                node.push_back(k.clone(), p);
-                node.keys[ii.add(1)] = k;
-                self.writer.update(right, right_entry.serialize()?)?;
-                self.writer.update(ci, child.serialize()?)?;
-                return Ok(Some((CursorEntry::Internal(node), successor)));
+        let (n, s) = node.split();
+        let ni = self.writer.insert(n.serialize()?)?;
+        self.writer.update(current, node.serialize()?)?;
+        Ok(Some((ni, s)))
+      }
+      CursorEntry::Leaf(mut node) => {
+        match node.keys.binary_search_by(|(k, _)| k.cmp(key)) {
+          Ok(i) => {
+            let inline = value.len().le(&MAX_INLINE_VALUE_SIZE);
+            node.keys[i].1 = match node.keys[i].1 {
+              LeafValue::Page(pi) if !inline => {
+                self.writer.update_value(pi, value)?;
+                return Ok(None);
               }
-            }
-
-            if let Some(left) = left {
-              let mut left_entry: CursorEntry = self.writer.get(left)?.deserialize()?;
-              left_entry
-                .as_internal()
-                .merge(node.keys.remove(ii), &mut child);
-              node.children.remove(ii.add(1));
-              self.writer.update(left, left_entry.serialize()?)?;
-              self.writer.release(ci)?;
-              return Ok(Some((CursorEntry::Internal(node), successor)));
-            }
-
-            if let Some(right) = right {
-              let mut right_entry: CursorEntry = self.writer.get(right)?.deserialize()?;
-              child.merge(
-                right_entry.as_internal().keys.remove(0),
-                &mut right_entry.as_internal(),
-              );
-              node.children.remove(ii.add(1));
-              self.writer.update(ci, child.serialize()?)?;
-              self.writer.release(right)?;
-              return Ok(Some((CursorEntry::Internal(node), successor)));
-            }
-
-            unreachable!()
-          }
-          CursorEntry::Leaf(mut child) => {
-            if child.len().ge(&MIN_NODE_LEN) {
-              if left.is_some() {
-                node.keys[ii] = child.top();
+              LeafValue::Page(pi) => {
+                self.writer.release_value(pi)?;
+                LeafValue::Inline(value)
               }
-              self.writer.update(ci, child.serialize()?)?;
-              return Ok(Some((CursorEntry::Internal(node), successor)));
+              LeafValue::Inline(_) => self.writer.insert_leaf_value(value)?,
             };
-            if let Some(left) = left {
-              let mut left_entry: CursorEntry = self.writer.get(left)?.deserialize()?;
-              if left_entry.as_leaf().len().gt(&MIN_NODE_LEN) {
-                let (k, p) = left_entry.as_leaf().pop_back().unwrap();
-                child.push_front(k.clone(), p);
-                node.keys[ii] = k;
-                self.writer.update(left, left_entry.serialize()?)?;
-                self.writer.update(ci, child.serialize()?)?;
-                return Ok(Some((CursorEntry::Internal(node), successor)));
-              }
-            }
+          }
+          Err(i) => {
+            let v = self.writer.insert_leaf_value(value)?;
+            node.keys.insert(i, (key.clone(), v));
+          }
+        };
 
-            if let Some(right) = right {
-              let mut right_entry: CursorEntry = self.writer.get(right)?.deserialize()?;
-              if right_entry.as_leaf().len().gt(&MIN_NODE_LEN) {
-                let (k, p) = right_entry.as_leaf().pop_front().unwrap();
-                child.push_back(k, p);
-                node.keys[ii.add(1)] = right_entry.top();
-                self.writer.update(right, right_entry.serialize()?)?;
-                self.writer.update(ci, child.serialize()?)?;
-                return Ok(Some((CursorEntry::Internal(node), successor)));
-              }
-            }
+        if node.byte_len().le(&MAX_NODE_SIZE) {
+          self.writer.update(current, node.serialize()?)?;
+          return Ok(None);
+        }
 
-            if let Some(left) = left {
-              let mut left_entry: CursorEntry = self.writer.get(left)?.deserialize()?;
-              left_entry.as_leaf().merge(&mut child);
-              node.keys.remove(ii);
-              node.children.remove(ii.add(1));
-              self.writer.update(left, left_entry.serialize()?)?;
-              self.writer.release(ci)?;
-              return Ok(Some((CursorEntry::Internal(node), successor)));
-            }
+        let after = node.next;
+        let (n, s) = node.split(current);
+        let ni = self.writer.insert(n.serialize()?)?;
+        node.set_next(ni);
+        self.writer.update(current, node.serialize()?)?;
+        self.link_prev(after, ni)?;
+        Ok(Some((ni, s)))
+      }
+    }
+  }
 
-            if let Some(right) = right {
-              let mut right_entry: CursorEntry = self.writer.get(right)?.deserialize()?;
-              child.merge(&mut right_entry.as_leaf());
-              node.keys.remove(ii.add(1));
-              node.children.remove(ii.add(2));
-              self.writer.update(ci, child.serialize()?)?;
-              self.writer.release(right)?;
-              return Ok(Some((CursorEntry::Internal(node), successor)));
-            }
-            unreachable!()
+  fn delete_root(&self, keyspace: &str, key: &Vec<u8>) -> Result {
+    let root = self.lock_root(keyspace)?;
+    let entry = match self._delete(root, key, &mut vec![])? {
+      Deleted::Underflow(entry) => entry,
+      Deleted::Split(ni, s) => return self.split_root(root, ni, s),
+      _ => return Ok(()),
+    };
+    match entry {
+      // the only child moves up into the root page, which the header points to.
+      CursorEntry::Internal(node) if node.len().eq(&0) => {
+        let child = node.children[0];
+        let page = self.writer.get_for_update(child)?;
+        self.writer.update(root, page)?;
+        self.writer.release(child)
+      }
+      entry => self.writer.update(root, entry.serialize()?),
+    }
+  }
+
+  /// Separators are left as they are when their key is deleted,
+  /// they still divide the children and rewriting them would change
+  /// the nodes above a child that did not merge.
+  fn _delete(
+    &self,
+    current: usize,
+    key: &Vec<u8>,
+    ancestors: &mut Vec<usize>,
+  ) -> Result<Deleted> {
+    let entry: CursorEntry = self.writer.get_for_update(current)?.deserialize()?;
+    self.crab(entry.can_shrink(), ancestors, current);
+    let node = match entry {
+      CursorEntry::Internal(mut node) => {
+        let (c, left, right) = node.find_family(key);
+        let ci = node.children[c];
+        match self._delete(ci, key, ancestors)? {
+          Deleted::Underflow(child) => {
+            self.rebalance(&mut node, c, ci, left, right, child)?
+          }
+          Deleted::Split(en, ek) => {
+            node.keys.insert(c, ek);
+            node.children.insert(c.add(1), en);
           }
+          deleted => return Ok(deleted),
         };
+        if node.byte_len().gt(&MAX_NODE_SIZE) {
+          let (n, s) = node.split();
+          let ni = self.writer.insert(n.serialize()?)?;
+          self.writer.update(current, node.serialize()?)?;
+          return Ok(Deleted::Split(ni, s));
+        }
+        CursorEntry::Internal(node)
       }
       CursorEntry::Leaf(mut node) => {
         let deleted = match node.delete(key) {
-          None => return Ok(None),
-          Some(i) => i,
+          None => return Ok(Deleted::NotFound),
+          Some(v) => v,
         };
-
-        self.writer.release(deleted)?;
-        let successor = node.top();
-        return Ok(Some((CursorEntry::Leaf(node), successor)));
+        if let LeafValue::Page(i) = deleted {
+          self.writer.release_value(i)?;
+        }
+        CursorEntry::Leaf(node)
       }
+    };
+
+    if node.byte_len().lt(&MIN_NODE_SIZE) {
+      return Ok(Deleted::Underflow(node));
     }
+    self.writer.update(current, node.serialize()?)?;
+    Ok(Deleted::Done)
   }
 
-  pub fn commit(&self) -> Result {
-    if self.committed.load(Ordering::SeqCst) {
-      return Err(Error::TransactionClosed);
-    }
+  /// Borrows an entry from a sibling of the child at c, or merges it into one.
+  /// A borrowed separator can be longer than the one it replaces,
+  /// so the node may need to split afterwards.
+  fn rebalance(
+    &self,
+    node: &mut InternalNode,
+    c: usize,
+    ci: usize,
+    left: Option<usize>,
+    right: Option<usize>,
+    child: CursorEntry,
+  ) -> Result {
+    match child {
+      CursorEntry::Internal(mut child) => {
+        if let Some(left) = left {
+          let mut left_entry: CursorEntry =
+            self.writer.get_for_update(left)?.deserialize()?;
+          if left_entry.as_internal().byte_len().gt(&LENDABLE_NODE_SIZE) {
+            let (k, p) = left_entry.as_internal().pop_back().unwrap();
+            child.push_front(node.keys[c.sub(1)].clone(), p);
+            node.keys[c.sub(1)] = k;
+            self.writer.update(left, left_entry.serialize()?)?;
+            return self.writer.update(ci, child.serialize()?);
+          }
+        }
 
-    logger::info(format!("cursor id {} commit start", self.writer.get_id()));
-    self.writer.commit()?;
-    self.committed.store(true, Ordering::SeqCst);
-    Ok(())
-  }
+        if let Some(right) = right {
+          let mut right_entry: CursorEntry =
+            self.writer.get_for_update(right)?.deserialize()?;
+          if right_entry.as_internal().byte_len().gt(&LENDABLE_NODE_SIZE) {
+            let (k, p) = right_entry.as_internal().pop_front().unwrap();
+            child.push_back(node.keys[c].clone(), p);
+            node.keys[c] = k;
+            self.writer.update(right, right_entry.serialize()?)?;
+            return self.writer.update(ci, child.serialize()?);
+          }
+        }
 
-  pub fn abort(&self) -> Result {
-    println!("abort not implemented");
-    Ok(())
-  }
-}
-impl Cursor {
-  fn get_index(&self, key: &Vec<u8>) -> Result<usize> {
-    let header: TreeHeader = self.writer.get(HEADER_INDEX)?.deserialize()?;
-    let mut index = header.get_root();
-    loop {
-      let entry: CursorEntry = self.writer.get(index)?.deserialize()?;
-      match entry.find_or_next(key) {
-        Ok(i) => return Ok(i),
-        Err(n) => match n {
-          Some(i) => index = i,
-          None => return Err(Error::NotFound),
-        },
+        if let Some(left) = left {
+          let mut left_entry: CursorEntry =
+            self.writer.get_for_update(left)?.deserialize()?;
+          left_entry
+            .as_internal()
+            .merge(node.keys.remove(c.sub(1)), &mut child);
+          node.children.remove(c);
+          self.writer.update(left, left_entry.serialize()?)?;
+          return self.writer.release(ci);
+        }
+
+        if let Some(right) = right {
+          let mut right_entry: CursorEntry =
+            self.writer.get_for_update(right)?.deserialize()?;
+          child.merge(node.keys.remove(c), right_entry.as_internal());
+          node.children.remove(c.add(1));
+          self.writer.update(ci, child.serialize()?)?;
+          return self.writer.release(right);
+        }
+
+        unreachable!()
+      }
+      CursorEntry::Leaf(mut child) => {
+        if let Some(left) = left {
+          let mut left_entry: CursorEntry =
+            self.writer.get_for_update(left)?.deserialize()?;
+          if left_entry.as_leaf().byte_len().gt(&LENDABLE_NODE_SIZE) {
+            let (k, p) = left_entry.as_leaf().pop_back().unwrap();
+            child.push_front(k.clone(), p);
+            node.keys[c.sub(1)] = k;
+            self.writer.update(left, left_entry.serialize()?)?;
+            return self.writer.update(ci, child.serialize()?);
+          }
+        }
+
+        if let Some(right) = right {
+          let mut right_entry: CursorEntry =
+            self.writer.get_for_update(right)?.deserialize()?;
+          if right_entry.as_leaf().byte_len().gt(&LENDABLE_NODE_SIZE) {
+            let (k, p) = right_entry.as_leaf().pop_front().unwrap();
+            child.push_back(k, p);
+            node.keys[c] = right_entry.top();
+            self.writer.update(right, right_entry.serialize()?)?;
+            return self.writer.update(ci, child.serialize()?);
+          }
+        }
+
+        if let Some(left) = left {
+          let mut left_entry: CursorEntry =
+            self.writer.get_for_update(left)?.deserialize()?;
+          left_entry.as_leaf().merge(&mut child);
+          node.keys.remove(c.sub(1));
+          node.children.remove(c);
+          self.writer.update(left, left_entry.serialize()?)?;
+          self.link_prev(child.next, left)?;
+          return self.writer.release(ci);
+        }
+
+        if let Some(right) = right {
+          let mut right_entry: CursorEntry =
+            self.writer.get_for_update(right)?.deserialize()?;
+          child.merge(right_entry.as_leaf());
+          node.keys.remove(c);
+          node.children.remove(c.add(1));
+          self.writer.update(ci, child.serialize()?)?;
+          self.link_prev(child.next, ci)?;
+          return self.writer.release(right);
+        }
+
+        unreachable!()
       }
     }
   }
+
+  fn link_prev(&self, index: Option<usize>, prev: usize) -> Result {
+    let index = match index {
+      Some(i) => i,
+      None => return Ok(()),
+    };
+    let mut entry: CursorEntry = self.writer.get_for_update(index)?.deserialize()?;
+    entry.as_leaf().set_prev(prev);
+    self.writer.update(index, entry.serialize()?)
+  }
 }
 impl Drop for Cursor {
   fn drop(&mut self) {
@@ -373,3 +727,308 @@ impl Drop for Cursor {
     self.abort().ok();
   }
 }
+
+pub struct Keyspace<'a> {
+  cursor: &'a Cursor,
+  name: String,
+}
+impl<'a> Keyspace<'a> {
+  pub fn get(&self, key: &Vec<u8>) -> Result<Vec<u8>> {
+    self.cursor.get_in(&self.name, key)
+  }
+
+  pub fn get_for_update(&self, key: &Vec<u8>) -> Result<Vec<u8>> {
+    self.cursor.get_for_update_in(&self.name, key)
+  }
+
+  pub fn range<R>(&self, range: R) -> Result<CursorIterator<'a>>
+  where
+    R: RangeBounds<Vec<u8>>,
+  {
+    self.cursor.range_in(&self.name, range)
+  }
+
+  pub fn scan_prefix(&self, prefix: &[u8]) -> Result<CursorIterator<'a>> {
+    self.cursor.scan_prefix_in(&self.name, prefix)
+  }
+
+  pub fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result {
+    self.cursor.insert_in(&self.name, key, value)
+  }
+
+  pub fn delete(&self, key: &Vec<u8>) -> Result<bool> {
+    self.cursor.delete_in(&self.name, key)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    collections::BTreeMap,
+    sync::Barrier,
+    thread,
+    time::{Duration, Instant},
+  };
+
+  use super::super::{IsolationLevel, TransactionOptions, DEFAULT_KEYSPACE};
+  use crate::{engine::TestEngine, Error};
+
+  /// Long keys keep the fan-out low, so a few hundred give internal levels.
+  fn key(i: usize) -> Vec<u8> {
+    let mut key = format!("key{:04}", i).into_bytes();
+    key.resize(200, b'-');
+    key
+  }
+
+  fn check(engine: &TestEngine, expected: &[usize]) {
+    let cursor = engine.new_transaction().unwrap();
+    let found: Vec<Vec<u8>> = cursor.range(..).unwrap().map(|e| e.unwrap().0).collect();
+    let want: Vec<Vec<u8>> = expected.iter().map(|&i| key(i)).collect();
+    assert_eq!(found, want);
+    for &i in expected {
+      assert_eq!(cursor.get(&key(i)).unwrap(), vec![i as u8; 20]);
+    }
+    cursor.commit().unwrap();
+  }
+
+  /// Deletes in the given order and checks the tree after every batch,
+  /// which walks through borrowing and merging on both sides at every level.
+  fn delete_in(name: &str, order: Vec<usize>) {
+    let engine = TestEngine::open(name);
+    let cursor = engine.new_transaction().unwrap();
+    for i in 0..600 {
+      cursor.insert(key(i), vec![i as u8; 20]).unwrap();
+    }
+    cursor.commit().unwrap();
+
+    let mut remaining: Vec<usize> = (0..600).collect();
+    for batch in order.chunks(50) {
+      let cursor = engine.new_transaction().unwrap();
+      for &i in batch {
+        assert!(cursor.delete(&key(i)).unwrap(), "{}", i);
+        assert!(!cursor.delete(&key(i)).unwrap(), "{}", i);
+      }
+      cursor.commit().unwrap();
+      remaining.retain(|i| !batch.contains(i));
+      check(&engine, &remaining);
+    }
+
+    let cursor = engine.new_transaction().unwrap();
+    assert!(matches!(cursor.get(&key(0)), Err(Error::NotFound)));
+    cursor.insert(key(0), vec![0; 20]).unwrap();
+    cursor.commit().unwrap();
+    check(&engine, &[0]);
+  }
+
+  #[test]
+  fn _1() {
+    delete_in("cursor-1", (0..600).collect());
+  }
+
+  #[test]
+  fn _2() {
+    delete_in("cursor-2", (0..600).rev().collect());
+  }
+
+  #[test]
+  fn _3() {
+    let mut order: Vec<usize> = (0..600).map(|i| i * 7 % 600).collect();
+    order.rotate_left(300);
+    delete_in("cursor-3", order);
+  }
+
+  #[test]
+  fn _4() {
+    let engine = TestEngine::open("cursor-4");
+    let cursor = engine.new_transaction().unwrap();
+    cursor.insert(key(0), vec![0; 20]).unwrap();
+    let first = cursor.savepoint().unwrap();
+    cursor.insert(key(1), vec![1; 20]).unwrap();
+    let second = cursor.savepoint().unwrap();
+    cursor.insert(key(2), vec![2; 20]).unwrap();
+    cursor.rollback_to(&first).unwrap();
+    assert!(matches!(cursor.rollback_to(&second), Err(Error::Invalid)));
+    cursor.insert(key(3), vec![3; 20]).unwrap();
+    // back to as many writes as it was taken at, still rolled back past.
+    assert!(matches!(cursor.rollback_to(&second), Err(Error::Invalid)));
+    cursor.commit().unwrap();
+    check(&engine, &[0, 3]);
+  }
+
+  fn batches(t: usize) -> Vec<Vec<usize>> {
+    let mine: Vec<usize> = (0..600).filter(|i| i % 4 == t).collect();
+    mine.chunks(10).map(|batch| batch.to_vec()).collect()
+  }
+
+  /// Threads split and merge the same nodes at once, every commit goes through.
+  #[test]
+  fn _5() {
+    let engine = TestEngine::open("cursor-5");
+    let barrier = Barrier::new(4);
+    thread::scope(|scope| {
+      for t in 0..4 {
+        let (engine, barrier) = (&engine, &barrier);
+        scope.spawn(move || {
+          barrier.wait();
+          for batch in batches(t) {
+            let cursor = engine.new_transaction().unwrap();
+            for &i in batch.iter() {
+              cursor.insert(key(i), vec![i as u8; 20]).unwrap();
+            }
+            cursor.commit().unwrap();
+          }
+          barrier.wait();
+          for batch in batches(t).into_iter().step_by(2) {
+            let cursor = engine.new_transaction().unwrap();
+            for &i in batch.iter() {
+              assert!(cursor.delete(&key(i)).unwrap());
+            }
+            cursor.commit().unwrap();
+          }
+        });
+      }
+    });
+
+    let mut remaining: Vec<usize> = (0..4)
+      .flat_map(|t| batches(t).into_iter().skip(1).step_by(2).flatten())
+      .collect();
+    remaining.sort();
+    check(&engine, &remaining);
+  }
+
+  /// Writes stay in the transaction until commit, so a neighbouring key
+  /// on the same leaf commits without waiting for it.
+  #[test]
+  fn _6() {
+    let engine = TestEngine::open("cursor-6");
+    let first = engine.new_transaction().unwrap();
+    first.insert(key(1), vec![1; 20]).unwrap();
+    assert!(first.delete(&key(1)).unwrap());
+    first.insert(key(1), vec![1; 20]).unwrap();
+    let found: Vec<Vec<u8>> = first.range(..).unwrap().map(|e| e.unwrap().0).collect();
+    assert_eq!(found, vec![key(1)]);
+
+    let started = Instant::now();
+    let second = engine.new_transaction().unwrap();
+    second.insert(key(2), vec![2; 20]).unwrap();
+    assert!(matches!(second.get(&key(1)), Err(Error::NotFound)));
+    second.commit().unwrap();
+    assert!(started.elapsed().lt(&Duration::from_millis(400)));
+
+    first.create_keyspace("other").unwrap();
+    first
+      .keyspace("other")
+      .unwrap()
+      .insert(key(3), vec![3; 20])
+      .unwrap();
+    let found: Vec<Vec<u8>> = first.range(..).unwrap().map(|e| e.unwrap().0).collect();
+    assert_eq!(found, vec![key(1)]);
+    let other = first.keyspace("other").unwrap();
+    assert_eq!(other.range(..).unwrap().count(), 1);
+    first.commit().unwrap();
+
+    check(&engine, &[1, 2]);
+    let cursor = engine.new_transaction().unwrap();
+    let other = cursor.keyspace("other").unwrap();
+    assert_eq!(other.get(&key(3)).unwrap(), vec![3; 20]);
+    cursor.commit().unwrap();
+  }
+
+  /// A key changed and changed back after the snapshot is still a conflict.
+  #[test]
+  fn _7() {
+    let engine = TestEngine::open("cursor-7");
+    let set = |value: u8| {
+      let cursor = engine.new_transaction().unwrap();
+      cursor.insert(key(0), vec![value; 20]).unwrap();
+      cursor.commit().unwrap();
+    };
+    set(0);
+
+    let options = TransactionOptions {
+      isolation: IsolationLevel::Snapshot,
+    };
+    let cursor = engine.new_transaction_with(options).unwrap();
+    assert_eq!(cursor.get(&key(0)).unwrap(), vec![0; 20]);
+    set(1);
+    set(0);
+    assert!(matches!(
+      cursor.insert(key(0), vec![2; 20]),
+      Err(Error::Conflict)
+    ));
+    cursor.abort().unwrap();
+
+    let cursor = engine.new_transaction_with(options).unwrap();
+    cursor.insert(key(0), vec![2; 20]).unwrap();
+    cursor.commit().unwrap();
+  }
+
+  /// Short and long keys mixed, so a separator a borrow puts into the parent
+  /// can be much longer than the one it replaces.
+  #[test]
+  fn _8() {
+    let engine = TestEngine::open("cursor-8");
+    let mixed = |i: usize| {
+      let mut key = format!("{:04}", i).into_bytes();
+      if i % 3 == 0 {
+        key.extend((0..896).map(|j| (i * 7 + j) as u8));
+      }
+      key
+    };
+
+    let mut expected = BTreeMap::new();
+    let mut seed = 7usize;
+    for _ in 0..60 {
+      let cursor = engine.new_transaction().unwrap();
+      for _ in 0..40 {
+        seed = seed
+          .wrapping_mul(6364136223846793005)
+          .wrapping_add(1442695040888963407);
+        let i = (seed >> 33) % 300;
+        match (seed >> 20) % 3 {
+          0 => {
+            assert_eq!(
+              cursor.delete(&mixed(i)).unwrap(),
+              expected.remove(&mixed(i)).is_some()
+            );
+          }
+          _ => {
+            cursor.insert(mixed(i), vec![i as u8; 20]).unwrap();
+            expected.insert(mixed(i), vec![i as u8; 20]);
+          }
+        }
+      }
+      cursor.commit().unwrap();
+    }
+
+    let cursor = engine.new_transaction().unwrap();
+    let found: Vec<(Vec<u8>, Vec<u8>)> =
+      cursor.range(..).unwrap().map(|e| e.unwrap()).collect();
+    assert_eq!(found, expected.into_iter().collect::<Vec<_>>());
+    cursor.commit().unwrap();
+  }
+
+  /// A failed apply is undone, so the pages it locked are free again
+  /// and the commit writes only what the transaction asked for.
+  #[test]
+  fn _9() {
+    let engine = TestEngine::open("cursor-9");
+    let cursor = engine.new_transaction().unwrap();
+    cursor.insert(key(0), vec![0; 20]).unwrap();
+    cursor.commit().unwrap();
+
+    let cursor = engine.new_transaction().unwrap();
+    cursor.insert(key(1), vec![1; 20]).unwrap();
+    let result = cursor.writer.apply(|| {
+      cursor.insert_root(DEFAULT_KEYSPACE, &key(2), vec![2; 20])?;
+      Err(Error::Invalid)
+    });
+    assert!(matches!(result, Err(Error::Invalid)));
+
+    let other = engine.new_transaction().unwrap();
+    other.insert(key(3), vec![3; 20]).unwrap();
+    other.commit().unwrap();
+    cursor.commit().unwrap();
+    check(&engine, &[0, 1, 3]);
+  }
+}
@@ -0,0 +1,212 @@
+use std::collections::BTreeMap;
+
+use crate::{Page, Result, Serializable};
+
+use super::{CursorEntry, CursorWriter, LeafValue, TreeHeader, ValuePage, HEADER_INDEX};
+
+enum Referrer {
+  Root(String),
+  Child(usize),
+  Value(usize, Vec<u8>),
+  Chain(usize),
+}
+
+/// What became of a page the run tried to move.
+enum Step {
+  Moved(usize),
+  /// it is no longer where it was collected from.
+  Skipped,
+  /// there is no free slot below it.
+  Full,
+}
+
+pub struct Defragmentation<'a> {
+  writer: &'a CursorWriter,
+  moved: BTreeMap<usize, usize>,
+}
+impl<'a> Defragmentation<'a> {
+  pub fn new(writer: &'a CursorWriter) -> Self {
+    Self {
+      writer,
+      moved: Default::default(),
+    }
+  }
+
+  /// Moves live pages from the tail into lower free slots,
+  /// returns how many pages were moved. Only a moved page and the pages
+  /// pointing to it stay locked, and a page another transaction holds is
+  /// skipped rather than waited for.
+  pub fn run(mut self) -> Result<usize> {
+    let referrers = self.collect()?;
+    for (&index, referrer) in referrers.iter().rev() {
+      let mut step = Step::Skipped;
+      let applied = self.writer.try_apply(|| {
+        step = self.relocate(index, referrer)?;
+        Ok(())
+      })?;
+      self.writer.unlock_unchanged();
+      match step {
+        Step::Moved(to) if applied => {
+          self.moved.insert(index, to);
+        }
+        Step::Full if applied => break,
+        _ => {}
+      }
+    }
+    Ok(self.moved.len())
+  }
+
+  fn relocate(&self, index: usize, referrer: &Referrer) -> Result<Step> {
+    let page = self.writer.get_for_update(index)?;
+    let from = match referrer {
+      Referrer::Root(_) => HEADER_INDEX,
+      Referrer::Child(i) | Referrer::Value(i, _) | Referrer::Chain(i) => self.resolve(*i),
+    };
+    let from_page = self.writer.get_for_update(from)?;
+    if !self.refers(&from_page, referrer, index) {
+      return Ok(Step::Skipped);
+    }
+
+    let mut links = (None, None);
+    if let Referrer::Root(_) | Referrer::Child(_) = referrer {
+      if let CursorEntry::Leaf(node) = page.deserialize()? {
+        links = (node.prev, node.next);
+      }
+    }
+    if let Some(prev) = links.0 {
+      let entry: CursorEntry = self.writer.get_for_update(prev)?.deserialize()?;
+      if let CursorEntry::Leaf(node) = entry {
+        if node.next.ne(&Some(index)) {
+          return Ok(Step::Skipped);
+        }
+      }
+    }
+    if let Some(next) = links.1 {
+      let entry: CursorEntry = self.writer.get_for_update(next)?.deserialize()?;
+      if let CursorEntry::Leaf(node) = entry {
+        if node.prev.ne(&Some(index)) {
+          return Ok(Step::Skipped);
+        }
+      }
+    }
+
+    let to = match self.writer.insert_below(page.copy(), index)? {
+      Some(i) => i,
+      None => return Ok(Step::Full),
+    };
+
+    match referrer {
+      Referrer::Root(name) => {
+        let mut header: TreeHeader = from_page.deserialize()?;
+        header.set_root(name, to);
+        self.writer.update(HEADER_INDEX, header.serialize()?)?;
+      }
+      Referrer::Child(_) => {
+        let mut entry: CursorEntry = from_page.deserialize()?;
+        for child in entry.as_internal().children.iter_mut() {
+          if (*child).eq(&index) {
+            *child = to;
+          }
+        }
+        self.writer.update(from, entry.serialize()?)?;
+      }
+      Referrer::Value(_, key) => {
+        let mut entry: CursorEntry = from_page.deserialize()?;
+        let node = entry.as_leaf();
+        if let Ok(i) = node.keys.binary_search_by(|(k, _)| k.cmp(key)) {
+          node.keys[i].1 = LeafValue::Page(to);
+        }
+        self.writer.update(from, entry.serialize()?)?;
+      }
+      Referrer::Chain(_) => {
+        let mut value: ValuePage = from_page.deserialize()?;
+        value.next = Some(to);
+        self.writer.update(from, value.serialize()?)?;
+      }
+    }
+
+    if let Some(prev) = links.0 {
+      let mut entry: CursorEntry = self.writer.get_latest(prev)?.deserialize()?;
+      entry.as_leaf().set_next(to);
+      self.writer.update(prev, entry.serialize()?)?;
+    }
+    if let Some(next) = links.1 {
+      let mut entry: CursorEntry = self.writer.get_latest(next)?.deserialize()?;
+      entry.as_leaf().set_prev(to);
+      self.writer.update(next, entry.serialize()?)?;
+    }
+
+    self.writer.release(index)?;
+    Ok(Step::Moved(to))
+  }
+
+  /// Whether the page still points to the index, as it did in the snapshot.
+  fn refers(&self, page: &Page, referrer: &Referrer, index: usize) -> bool {
+    match referrer {
+      Referrer::Root(name) => page
+        .deserialize::<TreeHeader, _>()
+        .and_then(|header| header.get_root(name))
+        .map(|root| root.eq(&index))
+        .unwrap_or(false),
+      Referrer::Child(_) => match page.deserialize() {
+        Ok(CursorEntry::Internal(node)) => node.children.contains(&index),
+        _ => false,
+      },
+      Referrer::Value(_, key) => match page.deserialize() {
+        Ok(CursorEntry::Leaf(node)) => node.find(key).eq(&Some(LeafValue::Page(index))),
+        _ => false,
+      },
+      Referrer::Chain(_) => match page.deserialize::<ValuePage, _>() {
+        Ok(value) => value.next.eq(&Some(index)),
+        _ => false,
+      },
+    }
+  }
+
+  fn resolve(&self, index: usize) -> usize {
+    self.moved.get(&index).copied().unwrap_or(index)
+  }
+
+  /// Reads the tree at the snapshot without locking it,
+  /// each page is checked against its latest version before it moves.
+  fn collect(&self) -> Result<BTreeMap<usize, Referrer>> {
+    let header: TreeHeader = self.writer.get(HEADER_INDEX)?.deserialize()?;
+    let mut referrers = BTreeMap::new();
+    let mut stack: Vec<(usize, Referrer)> = header
+      .roots()
+      .iter()
+      .map(|(name, &root)| (root, Referrer::Root(name.clone())))
+      .collect();
+
+    while let Some((index, referrer)) = stack.pop() {
+      let entry: CursorEntry = self.writer.get(index)?.deserialize()?;
+      match entry {
+        CursorEntry::Internal(node) => {
+          for &child in node.children.iter() {
+            stack.push((child, Referrer::Child(index)));
+          }
+        }
+        CursorEntry::Leaf(node) => {
+          for (key, value) in node.keys {
+            let mut current = match value {
+              LeafValue::Page(i) => i,
+              LeafValue::Inline(_) => continue,
+            };
+            referrers.insert(current, Referrer::Value(index, key));
+            loop {
+              let value: ValuePage = self.writer.get(current)?.deserialize()?;
+              let next = match value.next {
+                Some(i) => i,
+                None => break,
+              };
+              referrers.insert(next, Referrer::Chain(current));
+              current = next;
+            }
+          }
+        }
+      }
+      referrers.insert(index, referrer);
+    }
+    Ok(referrers)
+  }
+}
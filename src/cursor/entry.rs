@@ -1,12 +1,64 @@
-use std::ops::{Add, Sub};
+use std::ops::{Add, AddAssign, Div, Sub};
 
 use crate::{
-  disk::{Page, Serializable},
+  disk::{Page, PageScanner, PageWriter, Serializable},
   error::Error,
+  PAGE_SIZE,
 };
 
-pub static MAX_NODE_LEN: usize = 12;
-pub static MIN_NODE_LEN: usize = 6;
+/// the writer never fills the last byte of a page.
+pub const MAX_NODE_SIZE: usize = PAGE_SIZE - 1;
+pub const MIN_NODE_SIZE: usize = MAX_NODE_SIZE / 4;
+
+/// page marker, node type and entry count.
+const INTERNAL_HEADER_SIZE: usize = 4;
+/// internal header plus prev and next links.
+const LEAF_HEADER_SIZE: usize = INTERNAL_HEADER_SIZE + 16;
+
+/// keys are front coded against the previous key in the node,
+/// storing the shared prefix length and the suffix length.
+const KEY_OVERHEAD: usize = 4;
+
+/// values up to this size are stored next to their key instead of in a value page.
+pub const MAX_INLINE_VALUE_SIZE: usize = 64;
+/// key overhead, value tag, inline length and the largest inline value.
+const LEAF_ENTRY_OVERHEAD: usize = KEY_OVERHEAD + 3 + MAX_INLINE_VALUE_SIZE;
+
+/// at least four of the largest entries fit in a node, so that
+/// both halves of a split and every merge stay inside a page.
+pub const MAX_KEY_SIZE: usize =
+  (MAX_NODE_SIZE - LEAF_HEADER_SIZE) / 4 - LEAF_ENTRY_OVERHEAD;
+pub const MAX_ENTRY_SIZE: usize = MAX_KEY_SIZE + LEAF_ENTRY_OVERHEAD;
+
+/// a node can give an entry to its sibling without underflowing.
+pub const LENDABLE_NODE_SIZE: usize = MIN_NODE_SIZE + MAX_ENTRY_SIZE;
+
+fn shared_len(prev: &[u8], key: &[u8]) -> usize {
+  prev
+    .iter()
+    .zip(key.iter())
+    .take_while(|(a, b)| a.eq(b))
+    .count()
+}
+
+fn encoded_key_len(prev: &[u8], key: &[u8]) -> usize {
+  KEY_OVERHEAD.add(key.len()).sub(shared_len(prev, key))
+}
+
+fn write_key(wt: &mut PageWriter, prev: &[u8], key: &[u8]) -> Result<(), Error> {
+  let shared = shared_len(prev, key);
+  wt.write(&(shared as u16).to_be_bytes())?;
+  wt.write(&(key.len().sub(shared) as u16).to_be_bytes())?;
+  wt.write(&key[shared..])
+}
+
+fn read_key(sc: &mut PageScanner, prev: &[u8]) -> Result<Vec<u8>, Error> {
+  let shared = sc.read_u16()? as usize;
+  let n = sc.read_u16()? as usize;
+  let mut key = prev.get(..shared).ok_or(Error::Invalid)?.to_vec();
+  key.extend_from_slice(sc.read_n(n)?);
+  Ok(key)
+}
 
 #[derive(Debug)]
 pub enum CursorEntry {
@@ -30,7 +82,7 @@ impl Serializable for CursorEntry {
   }
 }
 impl CursorEntry {
-  pub fn find_or_next(&self, key: &Vec<u8>) -> Result<usize, Option<usize>> {
+  pub fn find_or_next(&self, key: &Vec<u8>) -> Result<LeafValue, Option<usize>> {
     match self {
       Self::Internal(node) => Err(Some(node.next(key))),
       Self::Leaf(node) => match node.find(key) {
@@ -54,6 +106,26 @@ impl CursorEntry {
     }
   }
 
+  pub fn byte_len(&self) -> usize {
+    match self {
+      Self::Leaf(node) => node.byte_len(),
+      Self::Internal(node) => node.byte_len(),
+    }
+  }
+
+  /// Has room for the largest entry or separator,
+  /// so an insert below cannot split it.
+  pub fn can_grow(&self) -> bool {
+    self.byte_len().add(MAX_ENTRY_SIZE).le(&MAX_NODE_SIZE)
+  }
+
+  /// Stays above the minimum after losing the largest entry or separator,
+  /// and has room for a longer separator lent from below,
+  /// so a delete below cannot make it borrow, merge or split.
+  pub fn can_shrink(&self) -> bool {
+    self.byte_len().ge(&LENDABLE_NODE_SIZE) && self.can_grow()
+  }
+
   pub fn top(&self) -> Vec<u8> {
     match self {
       Self::Leaf(node) => node.keys[0].0.clone(),
@@ -69,7 +141,18 @@ pub struct InternalNode {
 }
 impl InternalNode {
   pub fn split(&mut self) -> (CursorEntry, Vec<u8>) {
-    let c = self.keys.len().div_ceil(2);
+    let sizes = self.entry_sizes();
+    let half = self.byte_len().div(2);
+    let mut size = INTERNAL_HEADER_SIZE.add(8);
+    let mut c = 0;
+    while c.lt(&self.keys.len().sub(2)) {
+      size.add_assign(sizes[c]);
+      if size.ge(&half) {
+        break;
+      }
+      c.add_assign(1);
+    }
+    let c = c.max(1);
     let mut keys = self.keys.split_off(c);
     let m = keys.remove(0);
     let children = self.children.split_off(c.add(1));
@@ -104,6 +187,26 @@ impl InternalNode {
     self.keys.len()
   }
 
+  pub fn byte_len(&self) -> usize {
+    self
+      .entry_sizes()
+      .into_iter()
+      .fold(INTERNAL_HEADER_SIZE.add(8), |a, s| a.add(s))
+  }
+
+  fn entry_sizes(&self) -> Vec<usize> {
+    let mut prev: &[u8] = &[];
+    self
+      .keys
+      .iter()
+      .map(|k| {
+        let size = encoded_key_len(prev, k).add(8);
+        prev = k;
+        size
+      })
+      .collect()
+  }
+
   pub fn next(&self, key: &Vec<u8>) -> usize {
     let i = self
       .keys
@@ -113,22 +216,19 @@ impl InternalNode {
     self.children[i]
   }
 
-  pub fn find_family(
-    &self,
-    key: &Vec<u8>,
-  ) -> ((usize, usize), Option<usize>, Option<usize>) {
-    match self.keys.binary_search_by(|k| k.cmp(key)) {
-      Ok(i) => (
-        (i, self.children[i.add(1)]),
-        self.children.get(i).copied(),
-        self.children.get(i.add(2)).copied(),
-      ),
-      Err(i) => (
-        (i.sub(1), self.children[i]),
-        self.children.get(i.sub(1)).copied(),
-        self.children.get(i.add(1)).copied(),
-      ),
-    }
+  /// The position of the child holding the key, with its left and right siblings.
+  /// Keys equal to a separator live in the child on its right.
+  pub fn find_family(&self, key: &Vec<u8>) -> (usize, Option<usize>, Option<usize>) {
+    let c = self
+      .keys
+      .binary_search_by(|k| k.cmp(key))
+      .map(|i| i.add(1))
+      .unwrap_or_else(|i| i);
+    (
+      c,
+      c.checked_sub(1).map(|i| self.children[i]),
+      self.children.get(c.add(1)).copied(),
+    )
   }
 }
 
@@ -137,10 +237,11 @@ impl Serializable for InternalNode {
     let mut p = Page::new();
     let mut wt = p.writer();
     wt.write(&[2])?;
-    wt.write(&[self.keys.len() as u8])?;
+    wt.write(&(self.keys.len() as u16).to_be_bytes())?;
+    let mut prev: &[u8] = &[];
     for k in &self.keys {
-      wt.write(&[k.len() as u8])?;
-      wt.write(k.as_ref())?;
+      write_key(&mut wt, prev, k)?;
+      prev = k;
     }
     for &i in &self.children {
       wt.write(&i.to_be_bytes())?;
@@ -151,12 +252,12 @@ impl Serializable for InternalNode {
   fn deserialize(value: &Page) -> Result<Self, Error> {
     let mut sc = value.scanner();
     sc.read()?;
-    let kl = sc.read()?;
+    let kl = sc.read_u16()?;
     let mut keys = vec![];
     let mut children = vec![];
     for _ in 0..kl {
-      let n = sc.read()?;
-      keys.push(sc.read_n(n as usize)?.to_vec());
+      let key = read_key(&mut sc, keys.last().map(Vec::as_slice).unwrap_or(&[]))?;
+      keys.push(key);
     }
     for _ in 0..(kl + 1) {
       children.push(sc.read_usize()?);
@@ -166,9 +267,23 @@ impl Serializable for InternalNode {
   }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum LeafValue {
+  Inline(Vec<u8>),
+  Page(usize),
+}
+impl LeafValue {
+  fn byte_len(&self) -> usize {
+    match self {
+      Self::Inline(data) => data.len().add(3),
+      Self::Page(_) => 9,
+    }
+  }
+}
+
 #[derive(Debug)]
 pub struct LeafNode {
-  pub keys: Vec<(Vec<u8>, usize)>,
+  pub keys: Vec<(Vec<u8>, LeafValue)>,
   pub next: Option<usize>,
   pub prev: Option<usize>,
 }
@@ -181,12 +296,18 @@ impl LeafNode {
     }
   }
 
-  pub fn top(&self) -> Vec<u8> {
-    self.keys[0].0.clone()
-  }
-
   pub fn split(&mut self, current: usize) -> (CursorEntry, Vec<u8>) {
-    let c = self.keys.len().div_ceil(2);
+    let sizes = self.entry_sizes();
+    let half = self.byte_len().div(2);
+    let mut size = LEAF_HEADER_SIZE;
+    let mut c = 0;
+    while c.lt(&self.keys.len().sub(1)) {
+      size.add_assign(sizes[c]);
+      c.add_assign(1);
+      if size.ge(&half) {
+        break;
+      }
+    }
     let keys = self.keys.split_off(c);
     let m = keys[0].0.clone();
     let next = self.next.take();
@@ -204,7 +325,11 @@ impl LeafNode {
     self.next = Some(next);
   }
 
-  pub fn delete(&mut self, key: &Vec<u8>) -> Option<usize> {
+  pub fn set_prev(&mut self, prev: usize) {
+    self.prev = Some(prev);
+  }
+
+  pub fn delete(&mut self, key: &Vec<u8>) -> Option<LeafValue> {
     if let Ok(i) = self.keys.binary_search_by(|(k, _)| k.cmp(key)) {
       return Some(self.keys.remove(i).1);
     }
@@ -220,28 +345,48 @@ impl LeafNode {
     self.keys.len()
   }
 
-  pub fn find(&self, key: &Vec<u8>) -> Option<usize> {
+  pub fn byte_len(&self) -> usize {
+    self
+      .entry_sizes()
+      .into_iter()
+      .fold(LEAF_HEADER_SIZE, |a, s| a.add(s))
+  }
+
+  fn entry_sizes(&self) -> Vec<usize> {
+    let mut prev: &[u8] = &[];
+    self
+      .keys
+      .iter()
+      .map(|(k, v)| {
+        let size = encoded_key_len(prev, k).add(v.byte_len());
+        prev = k;
+        size
+      })
+      .collect()
+  }
+
+  pub fn find(&self, key: &Vec<u8>) -> Option<LeafValue> {
     self
       .keys
       .binary_search_by(|(k, _)| k.cmp(key))
       .ok()
-      .map(|i| self.keys[i].1)
+      .map(|i| self.keys[i].1.clone())
   }
 
-  pub fn pop_front(&mut self) -> Option<(Vec<u8>, usize)> {
+  pub fn pop_front(&mut self) -> Option<(Vec<u8>, LeafValue)> {
     if self.len().eq(&0) {
       return None;
     }
     Some(self.keys.remove(0))
   }
-  pub fn pop_back(&mut self) -> Option<(Vec<u8>, usize)> {
+  pub fn pop_back(&mut self) -> Option<(Vec<u8>, LeafValue)> {
     self.keys.pop()
   }
-  pub fn push_front(&mut self, key: Vec<u8>, index: usize) {
-    self.keys.insert(0, (key, index));
+  pub fn push_front(&mut self, key: Vec<u8>, value: LeafValue) {
+    self.keys.insert(0, (key, value));
   }
-  pub fn push_back(&mut self, key: Vec<u8>, index: usize) {
-    self.keys.push((key, index));
+  pub fn push_back(&mut self, key: Vec<u8>, value: LeafValue) {
+    self.keys.push((key, value));
   }
 }
 impl Serializable for LeafNode {
@@ -249,11 +394,22 @@ impl Serializable for LeafNode {
     let mut p = Page::new();
     let mut wt = p.writer();
     wt.write(&[1])?;
-    wt.write(&[self.keys.len() as u8])?;
-    for (k, i) in &self.keys {
-      wt.write(&[k.len() as u8])?;
-      wt.write(k.as_ref())?;
-      wt.write(&i.to_be_bytes())?;
+    wt.write(&(self.keys.len() as u16).to_be_bytes())?;
+    let mut prev: &[u8] = &[];
+    for (k, v) in &self.keys {
+      write_key(&mut wt, prev, k)?;
+      prev = k;
+      match v {
+        LeafValue::Inline(data) => {
+          wt.write(&[1])?;
+          wt.write(&(data.len() as u16).to_be_bytes())?;
+          wt.write(data.as_ref())?;
+        }
+        LeafValue::Page(i) => {
+          wt.write(&[0])?;
+          wt.write(&i.to_be_bytes())?;
+        }
+      }
     }
     let prev = self.prev.unwrap_or(0);
     wt.write(&prev.to_be_bytes())?;
@@ -266,12 +422,21 @@ impl Serializable for LeafNode {
     let mut sc = value.scanner();
     sc.read()?;
     let mut keys = vec![];
-    let kl = sc.read()?;
+    let kl = sc.read_u16()?;
     for _ in 0..kl {
-      let n = sc.read()?;
-      let k = sc.read_n(n as usize)?.to_vec();
-      let i = sc.read_usize()?;
-      keys.push((k, i));
+      let prev = keys
+        .last()
+        .map(|(k, _): &(Vec<u8>, LeafValue)| k.as_slice());
+      let k = read_key(&mut sc, prev.unwrap_or(&[]))?;
+      let v = match sc.read()? {
+        0 => LeafValue::Page(sc.read_usize()?),
+        1 => {
+          let n = sc.read_u16()?;
+          LeafValue::Inline(sc.read_n(n as usize)?.to_vec())
+        }
+        _ => return Err(Error::Invalid),
+      };
+      keys.push((k, v));
     }
     let prev = sc.read_usize()?;
     let prev = if prev.eq(&0) { None } else { Some(prev) };
@@ -280,3 +445,119 @@ impl Serializable for LeafNode {
     Ok(Self { keys, prev, next })
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use crate::Serializable;
+
+  use super::{
+    InternalNode, LeafNode, LeafValue, MAX_INLINE_VALUE_SIZE, MAX_KEY_SIZE, MAX_NODE_SIZE,
+  };
+
+  #[test]
+  fn _1() {
+    let keys: Vec<Vec<u8>> = (0..4).map(|i| vec![i as u8; MAX_KEY_SIZE]).collect();
+
+    let leaf = LeafNode {
+      keys: keys
+        .iter()
+        .cloned()
+        .map(|k| (k, LeafValue::Inline(vec![9; MAX_INLINE_VALUE_SIZE])))
+        .collect(),
+      prev: Some(3),
+      next: Some(5),
+    };
+    assert!(leaf.byte_len() <= MAX_NODE_SIZE);
+    let decoded: LeafNode = leaf.serialize().unwrap().deserialize().unwrap();
+    assert_eq!(decoded.keys, leaf.keys);
+    assert_eq!(decoded.prev, Some(3));
+    assert_eq!(decoded.next, Some(5));
+
+    let internal = InternalNode {
+      keys: keys.clone(),
+      children: (0..5).collect(),
+    };
+    assert!(internal.byte_len() <= MAX_NODE_SIZE);
+    let decoded: InternalNode = internal.serialize().unwrap().deserialize().unwrap();
+    assert_eq!(decoded.keys, internal.keys);
+    assert_eq!(decoded.children, internal.children);
+  }
+
+  #[test]
+  fn _2() {
+    let mut leaf = LeafNode::empty();
+    leaf.keys.push((vec![], LeafValue::Page(1)));
+    let key_len = MAX_NODE_SIZE - leaf.byte_len();
+    leaf.keys[0].0 = vec![1; key_len];
+    assert_eq!(leaf.byte_len(), MAX_NODE_SIZE);
+    assert!(leaf.serialize().is_ok());
+
+    leaf.keys[0].0.push(1);
+    assert!(leaf.serialize().is_err());
+  }
+
+  #[test]
+  fn _3() {
+    let mut leaf = LeafNode::empty();
+    let mut i = 0u16;
+    while leaf.byte_len() <= MAX_NODE_SIZE {
+      let len = (i as usize * 37) % MAX_KEY_SIZE;
+      let mut key = i.to_be_bytes().to_vec();
+      key.extend(vec![0; len]);
+      let value = match i % 2 {
+        0 => LeafValue::Page(i as usize),
+        _ => LeafValue::Inline(vec![1; i as usize % MAX_INLINE_VALUE_SIZE]),
+      };
+      leaf.keys.push((key, value));
+      i += 1;
+    }
+
+    let (mut right, _) = leaf.split(1);
+    assert!(leaf.byte_len() <= MAX_NODE_SIZE);
+    assert!(right.as_leaf().byte_len() <= MAX_NODE_SIZE);
+    assert!(leaf.serialize().is_ok());
+    assert!(right.serialize().is_ok());
+  }
+
+  #[test]
+  fn _4() {
+    let prefix = vec![b'k'; 100];
+    let keys: Vec<Vec<u8>> = (0..200u16)
+      .map(|i| [prefix.as_slice(), &i.to_be_bytes()].concat())
+      .collect();
+
+    let leaf = LeafNode {
+      keys: keys
+        .iter()
+        .cloned()
+        .map(|k| (k, LeafValue::Page(1)))
+        .collect(),
+      prev: None,
+      next: None,
+    };
+    assert!(leaf.byte_len() <= MAX_NODE_SIZE);
+    let decoded: LeafNode = leaf.serialize().unwrap().deserialize().unwrap();
+    assert_eq!(decoded.keys, leaf.keys);
+
+    let internal = InternalNode {
+      keys: keys.clone(),
+      children: (0..=keys.len()).collect(),
+    };
+    assert!(internal.byte_len() <= MAX_NODE_SIZE);
+    let decoded: InternalNode = internal.serialize().unwrap().deserialize().unwrap();
+    assert_eq!(decoded.keys, internal.keys);
+  }
+
+  #[test]
+  fn _5() {
+    let internal = InternalNode {
+      keys: vec![vec![10], vec![20], vec![30]],
+      children: vec![100, 101, 102, 103],
+    };
+    assert_eq!(internal.find_family(&vec![5]), (0, None, Some(101)));
+    assert_eq!(internal.find_family(&vec![10]), (1, Some(100), Some(102)));
+    assert_eq!(internal.find_family(&vec![15]), (1, Some(100), Some(102)));
+    assert_eq!(internal.find_family(&vec![30]), (3, Some(102), None));
+    assert_eq!(internal.find_family(&vec![99]), (3, Some(102), None));
+  }
+}
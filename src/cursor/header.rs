@@ -1,30 +1,63 @@
-use std::ops::Add;
+use std::{collections::BTreeMap, ops::Add};
 
 use crate::{
-  disk::{Page, Serializable},
+  disk::{Page, Serializable, PAGE_SIZE},
   error::Error,
 };
 
 pub static HEADER_INDEX: usize = 0;
+pub static DEFAULT_KEYSPACE: &str = "default";
+
+pub const MAX_KEYSPACE_NAME_SIZE: usize = 64;
+/// Every root has to fit in the header page at the longest name.
+pub const MAX_KEYSPACE_COUNT: usize = (PAGE_SIZE - 2) / (2 + MAX_KEYSPACE_NAME_SIZE + 8);
 
 #[derive(Debug)]
 pub struct TreeHeader {
-  root: usize,
+  roots: BTreeMap<String, usize>,
 }
 
 impl TreeHeader {
   pub fn initial_state() -> Self {
     Self {
-      root: HEADER_INDEX.add(1),
+      roots: BTreeMap::from([(DEFAULT_KEYSPACE.to_string(), HEADER_INDEX.add(1))]),
     }
   }
 
-  pub fn get_root(&self) -> usize {
-    self.root
+  pub fn get_root(&self, keyspace: &str) -> Result<usize, Error> {
+    self
+      .roots
+      .get(keyspace)
+      .copied()
+      .ok_or(Error::KeyspaceNotFound)
+  }
+
+  pub fn roots(&self) -> &BTreeMap<String, usize> {
+    &self.roots
+  }
+
+  pub fn set_root(&mut self, keyspace: &str, index: usize) {
+    self.roots.insert(keyspace.to_string(), index);
+  }
+
+  /// Checks the keyspace can be added before any page is written for it.
+  pub fn check_new_root(&self, keyspace: &str) -> Result<(), Error> {
+    if keyspace.is_empty() || keyspace.len().gt(&MAX_KEYSPACE_NAME_SIZE) {
+      return Err(Error::InvalidKeyspaceName);
+    }
+    if self.roots.contains_key(keyspace) {
+      return Err(Error::KeyspaceAlreadyExists);
+    }
+    if self.roots.len().ge(&MAX_KEYSPACE_COUNT) {
+      return Err(Error::TooManyKeyspaces);
+    }
+    Ok(())
   }
 
-  pub fn set_root(&mut self, index: usize) {
-    self.root = index
+  pub fn add_root(&mut self, keyspace: &str, index: usize) -> Result<(), Error> {
+    self.check_new_root(keyspace)?;
+    self.set_root(keyspace, index);
+    Ok(())
   }
 }
 
@@ -32,14 +65,88 @@ impl Serializable for TreeHeader {
   fn serialize(&self) -> Result<Page, Error> {
     let mut p = Page::new();
     let mut wt = p.writer();
-    wt.write(&self.root.to_be_bytes())?;
+    wt.write(&(self.roots.len() as u16).to_be_bytes())?;
+    for (name, root) in &self.roots {
+      wt.write(&(name.len() as u16).to_be_bytes())?;
+      wt.write(name.as_bytes())?;
+      wt.write(&root.to_be_bytes())?;
+    }
     Ok(p)
   }
 
   fn deserialize(value: &Page) -> Result<Self, Error> {
+    if value.is_empty() {
+      return Err(Error::NotFound);
+    }
+
     let mut s = value.scanner();
-    let root = s.read_usize()?;
+    let mut roots = BTreeMap::new();
+    for _ in 0..s.read_u16()? {
+      let n = s.read_u16()?;
+      let name =
+        String::from_utf8(s.read_n(n as usize)?.to_vec()).map_err(|_| Error::Invalid)?;
+      roots.insert(name, s.read_usize()?);
+    }
+
+    Ok(TreeHeader { roots })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::engine::TestEngine;
+
+  #[test]
+  fn _1() {
+    let mut header = TreeHeader::initial_state();
+    for i in 1..MAX_KEYSPACE_COUNT {
+      let name = format!("{:0>1$}", i, MAX_KEYSPACE_NAME_SIZE);
+      header.add_root(&name, i).unwrap();
+    }
+    assert!(matches!(
+      header.add_root("one more", 0),
+      Err(Error::TooManyKeyspaces)
+    ));
+
+    let decoded: TreeHeader = header.serialize().unwrap().deserialize().unwrap();
+    assert_eq!(decoded.roots(), header.roots());
+  }
+
+  #[test]
+  fn _2() {
+    let engine = TestEngine::open("header-2");
+    engine.create_keyspace("users").unwrap();
+    assert!(matches!(
+      engine.create_keyspace("users"),
+      Err(Error::KeyspaceAlreadyExists)
+    ));
+    assert!(matches!(
+      engine.create_keyspace(""),
+      Err(Error::InvalidKeyspaceName)
+    ));
+    assert!(matches!(
+      engine.create_keyspace(&"a".repeat(MAX_KEYSPACE_NAME_SIZE + 1)),
+      Err(Error::InvalidKeyspaceName)
+    ));
+
+    let cursor = engine.new_transaction().unwrap();
+    cursor.insert(b"k".to_vec(), b"default".to_vec()).unwrap();
+    let users = cursor.keyspace("users").unwrap();
+    users.insert(b"k".to_vec(), b"users".to_vec()).unwrap();
+    assert!(matches!(
+      cursor.keyspace("missing"),
+      Err(Error::KeyspaceNotFound)
+    ));
+    cursor.commit().unwrap();
 
-    Ok(TreeHeader { root })
+    let cursor = engine.new_transaction().unwrap();
+    assert_eq!(cursor.get(&b"k".to_vec()).unwrap(), b"default".to_vec());
+    let users = cursor.keyspace("users").unwrap();
+    assert_eq!(users.get(&b"k".to_vec()).unwrap(), b"users".to_vec());
+    assert!(users.delete(&b"k".to_vec()).unwrap());
+    assert!(matches!(users.get(&b"k".to_vec()), Err(Error::NotFound)));
+    assert_eq!(cursor.get(&b"k".to_vec()).unwrap(), b"default".to_vec());
+    cursor.commit().unwrap();
   }
 }
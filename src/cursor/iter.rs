@@ -0,0 +1,497 @@
+use std::{
+  collections::BTreeMap,
+  marker::PhantomData,
+  ops::{Add, Bound, RangeBounds, Sub},
+};
+
+use crate::Result;
+
+use super::{CursorEntry, CursorReader, LeafNode};
+
+type Entry = (Vec<u8>, Vec<u8>);
+
+pub struct CursorIterator<'a> {
+  /// stays at the snapshot the iterator was created at, even when
+  /// the cursor moves its own under read committed.
+  reader: CursorReader,
+  cursor: PhantomData<&'a CursorReader>,
+  root: Option<usize>,
+  start: Bound<Vec<u8>>,
+  end: Bound<Vec<u8>>,
+  front: Option<(LeafNode, usize)>,
+  back: Option<(LeafNode, usize)>,
+  /// the transaction's own writes in the range, None for deletions.
+  pending: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+  /// the next entry of the tree from each end, once read.
+  peeked_front: Option<Option<Entry>>,
+  peeked_back: Option<Option<Entry>>,
+  done: bool,
+}
+impl<'a> CursorIterator<'a> {
+  /// A keyspace without a root yet iterates over the pending writes only.
+  pub fn new(
+    reader: &'a CursorReader,
+    root: Option<usize>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+  ) -> Self {
+    Self {
+      reader: reader.pin(),
+      cursor: PhantomData,
+      root,
+      start,
+      end,
+      front: None,
+      back: None,
+      pending: Default::default(),
+      peeked_front: None,
+      peeked_back: None,
+      done: false,
+    }
+  }
+
+  pub fn prefix(reader: &'a CursorReader, root: Option<usize>, prefix: &[u8]) -> Self {
+    let mut end = prefix.to_vec();
+    while let Some(b) = end.pop() {
+      if b.lt(&u8::MAX) {
+        end.push(b.add(1));
+        break;
+      }
+    }
+    let end = match end.is_empty() {
+      true => Bound::Unbounded,
+      false => Bound::Excluded(end),
+    };
+    Self::new(reader, root, Bound::Included(prefix.to_vec()), end)
+  }
+
+  pub fn bounds(&self) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    (self.start.clone(), self.end.clone())
+  }
+
+  /// Merges the writes the tree does not have yet, which take precedence.
+  pub fn with_pending(mut self, pending: BTreeMap<Vec<u8>, Option<Vec<u8>>>) -> Self {
+    self.pending = pending;
+    self
+  }
+
+  fn seek_front(&self) -> Result<(LeafNode, usize)> {
+    let mut index = match self.root {
+      Some(i) => i,
+      None => return Ok((LeafNode::empty(), 0)),
+    };
+    loop {
+      let entry: CursorEntry = self.reader.get(index)?.deserialize()?;
+      match entry {
+        CursorEntry::Internal(node) => {
+          index = match &self.start {
+            Bound::Included(k) | Bound::Excluded(k) => node.next(k),
+            Bound::Unbounded => node.children[0],
+          };
+        }
+        CursorEntry::Leaf(node) => {
+          let pos = match &self.start {
+            Bound::Included(k) => node
+              .keys
+              .binary_search_by(|(e, _)| e.cmp(k))
+              .unwrap_or_else(|i| i),
+            Bound::Excluded(k) => node
+              .keys
+              .binary_search_by(|(e, _)| e.cmp(k))
+              .map(|i| i.add(1))
+              .unwrap_or_else(|i| i),
+            Bound::Unbounded => 0,
+          };
+          return Ok((node, pos));
+        }
+      }
+    }
+  }
+
+  fn tree_front(&mut self) -> Result<Option<Entry>> {
+    let (mut leaf, mut pos) = match self.front.take() {
+      Some(v) => v,
+      None => self.seek_front()?,
+    };
+
+    while pos.ge(&leaf.len()) {
+      let next = match leaf.next {
+        Some(i) => i,
+        None => return Ok(None),
+      };
+      leaf = self.reader.get(next)?.deserialize()?;
+      pos = 0;
+    }
+
+    let (key, index) = leaf.keys[pos].clone();
+    let in_range = match &self.end {
+      Bound::Included(k) => key.le(k),
+      Bound::Excluded(k) => key.lt(k),
+      Bound::Unbounded => true,
+    };
+    if !in_range {
+      return Ok(None);
+    }
+
+    let value = self.reader.get_leaf_value(index)?;
+    self.front = Some((leaf, pos.add(1)));
+    Ok(Some((key, value)))
+  }
+
+  /// The smaller of the next tree and pending entries, skipping deletions.
+  /// Bounds move only as entries are returned, so both ends stop where they meet.
+  fn step_front(&mut self) -> Result<Option<Entry>> {
+    loop {
+      let tree = match self.peeked_front.take() {
+        Some(peeked) => peeked,
+        None => self.tree_front()?,
+      };
+      let first = self.pending.keys().next().cloned();
+      let from_pending = match (&tree, &first) {
+        (_, None) => false,
+        (None, Some(_)) => true,
+        (Some((key, _)), Some(p)) => p.le(key),
+      };
+      let (key, value) = match (from_pending, tree) {
+        (true, tree) => {
+          let key = first.unwrap();
+          if tree.as_ref().map(|(k, _)| k.ne(&key)).unwrap_or(true) {
+            self.peeked_front = Some(tree);
+          }
+          let value = self.pending.remove(&key).unwrap();
+          (key, value)
+        }
+        (false, Some((key, value))) => (key, Some(value)),
+        (false, None) => return Ok(None),
+      };
+
+      if !self.bounds().contains(&key) {
+        return Ok(None);
+      }
+      self.start = Bound::Excluded(key.clone());
+      if let Some(value) = value {
+        return Ok(Some((key, value)));
+      }
+    }
+  }
+
+  fn seek_back(&self) -> Result<(LeafNode, usize)> {
+    let mut index = match self.root {
+      Some(i) => i,
+      None => return Ok((LeafNode::empty(), 0)),
+    };
+    loop {
+      let entry: CursorEntry = self.reader.get(index)?.deserialize()?;
+      match entry {
+        CursorEntry::Internal(node) => {
+          index = match &self.end {
+            Bound::Included(k) | Bound::Excluded(k) => node.next(k),
+            Bound::Unbounded => node.children[node.children.len().sub(1)],
+          };
+        }
+        CursorEntry::Leaf(node) => {
+          let pos = match &self.end {
+            Bound::Included(k) => node
+              .keys
+              .binary_search_by(|(e, _)| e.cmp(k))
+              .map(|i| i.add(1))
+              .unwrap_or_else(|i| i),
+            Bound::Excluded(k) => node
+              .keys
+              .binary_search_by(|(e, _)| e.cmp(k))
+              .unwrap_or_else(|i| i),
+            Bound::Unbounded => node.len(),
+          };
+          return Ok((node, pos));
+        }
+      }
+    }
+  }
+
+  fn tree_back(&mut self) -> Result<Option<Entry>> {
+    let (mut leaf, mut pos) = match self.back.take() {
+      Some(v) => v,
+      None => self.seek_back()?,
+    };
+
+    while pos.eq(&0) {
+      let prev = match leaf.prev {
+        Some(i) => i,
+        None => return Ok(None),
+      };
+      leaf = self.reader.get(prev)?.deserialize()?;
+      pos = leaf.len();
+    }
+
+    let (key, index) = leaf.keys[pos.sub(1)].clone();
+    let in_range = match &self.start {
+      Bound::Included(k) => key.ge(k),
+      Bound::Excluded(k) => key.gt(k),
+      Bound::Unbounded => true,
+    };
+    if !in_range {
+      return Ok(None);
+    }
+
+    let value = self.reader.get_leaf_value(index)?;
+    self.back = Some((leaf, pos.sub(1)));
+    Ok(Some((key, value)))
+  }
+
+  fn step_back(&mut self) -> Result<Option<Entry>> {
+    loop {
+      let tree = match self.peeked_back.take() {
+        Some(peeked) => peeked,
+        None => self.tree_back()?,
+      };
+      let last = self.pending.keys().next_back().cloned();
+      let from_pending = match (&tree, &last) {
+        (_, None) => false,
+        (None, Some(_)) => true,
+        (Some((key, _)), Some(p)) => p.ge(key),
+      };
+      let (key, value) = match (from_pending, tree) {
+        (true, tree) => {
+          let key = last.unwrap();
+          if tree.as_ref().map(|(k, _)| k.ne(&key)).unwrap_or(true) {
+            self.peeked_back = Some(tree);
+          }
+          let value = self.pending.remove(&key).unwrap();
+          (key, value)
+        }
+        (false, Some((key, value))) => (key, Some(value)),
+        (false, None) => return Ok(None),
+      };
+
+      if !self.bounds().contains(&key) {
+        return Ok(None);
+      }
+      self.end = Bound::Excluded(key.clone());
+      if let Some(value) = value {
+        return Ok(Some((key, value)));
+      }
+    }
+  }
+
+  fn finish(
+    &mut self,
+    result: Result<Option<(Vec<u8>, Vec<u8>)>>,
+  ) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+    match result {
+      Ok(Some(v)) => Some(Ok(v)),
+      Ok(None) => {
+        self.done = true;
+        None
+      }
+      Err(err) => {
+        self.done = true;
+        Some(Err(err))
+      }
+    }
+  }
+}
+
+impl<'a> Drop for CursorIterator<'a> {
+  fn drop(&mut self) {
+    self.reader.unpin();
+  }
+}
+
+impl<'a> Iterator for CursorIterator<'a> {
+  type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.done {
+      return None;
+    }
+
+    let result = self.step_front();
+    self.finish(result)
+  }
+}
+
+impl<'a> DoubleEndedIterator for CursorIterator<'a> {
+  fn next_back(&mut self) -> Option<Self::Item> {
+    if self.done {
+      return None;
+    }
+
+    let result = self.step_back();
+    self.finish(result)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{collections::BTreeMap, ops::Bound};
+
+  use crate::engine::TestEngine;
+
+  fn key(i: usize) -> Vec<u8> {
+    format!("key{:04}", i).into_bytes()
+  }
+
+  /// Keys spread over several leaves, every other one left out.
+  fn fill(engine: &TestEngine) -> BTreeMap<Vec<u8>, Vec<u8>> {
+    let cursor = engine.new_transaction().unwrap();
+    let mut expected = BTreeMap::new();
+    for i in (0..400).step_by(2) {
+      let value = vec![i as u8; 40];
+      cursor.insert(key(i), value.clone()).unwrap();
+      expected.insert(key(i), value);
+    }
+    cursor.commit().unwrap();
+    expected
+  }
+
+  fn bounds() -> Vec<(Bound<Vec<u8>>, Bound<Vec<u8>>)> {
+    let mut bounds = vec![];
+    for start in [
+      Bound::Unbounded,
+      Bound::Included(key(10)),
+      Bound::Included(key(11)),
+      Bound::Excluded(key(10)),
+    ] {
+      for end in [
+        Bound::Unbounded,
+        Bound::Included(key(350)),
+        Bound::Included(key(351)),
+        Bound::Excluded(key(350)),
+      ] {
+        bounds.push((start.clone(), end));
+      }
+    }
+    bounds
+  }
+
+  #[test]
+  fn _1() {
+    let engine = TestEngine::open("iter-1");
+    let expected = fill(&engine);
+
+    let cursor = engine.new_transaction().unwrap();
+    for range in bounds() {
+      let found: Vec<(Vec<u8>, Vec<u8>)> = cursor
+        .range(range.clone())
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+      let want: Vec<(Vec<u8>, Vec<u8>)> = expected
+        .range(range.clone())
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+      assert_eq!(found, want, "{:?}", range);
+    }
+    assert_eq!(cursor.range(key(20)..key(20)).unwrap().count(), 0);
+    assert_eq!(cursor.range(key(500)..).unwrap().count(), 0);
+    cursor.commit().unwrap();
+  }
+
+  #[test]
+  fn _2() {
+    let engine = TestEngine::open("iter-2");
+    let mut expected = fill(&engine);
+
+    let cursor = engine.new_transaction().unwrap();
+    for i in (0..400).step_by(6) {
+      assert!(cursor.delete(&key(i)).unwrap());
+      expected.remove(&key(i));
+    }
+    cursor.commit().unwrap();
+
+    let cursor = engine.new_transaction().unwrap();
+    for range in bounds() {
+      let found: Vec<Vec<u8>> = cursor
+        .range(range.clone())
+        .unwrap()
+        .rev()
+        .map(|r| r.unwrap().0)
+        .collect();
+      let want: Vec<Vec<u8>> = expected
+        .range(range.clone())
+        .rev()
+        .map(|(k, _)| k.clone())
+        .collect();
+      assert_eq!(found, want, "{:?}", range);
+
+      let mut iter = cursor.range(range.clone()).unwrap();
+      let (mut front, mut back) = (vec![], vec![]);
+      loop {
+        match iter.next() {
+          Some(r) => front.push(r.unwrap().0),
+          None => break,
+        }
+        match iter.next_back() {
+          Some(r) => back.push(r.unwrap().0),
+          None => break,
+        }
+      }
+      assert!(iter.next().is_none());
+      assert!(iter.next_back().is_none());
+      back.reverse();
+      front.append(&mut back);
+      let want: Vec<Vec<u8>> = expected
+        .range(range.clone())
+        .map(|(k, _)| k.clone())
+        .collect();
+      assert_eq!(front, want, "{:?}", range);
+    }
+    cursor.commit().unwrap();
+  }
+
+  #[test]
+  fn _3() {
+    let engine = TestEngine::open("iter-3");
+    let keys: Vec<Vec<u8>> = vec![
+      vec![],
+      vec![0x00],
+      vec![0x61],
+      vec![0x61, 0x00],
+      vec![0x61, 0xFF],
+      vec![0x61, 0xFF, 0x01],
+      vec![0x62],
+      vec![0xFE, 0xFF],
+      vec![0xFF],
+      vec![0xFF, 0x00],
+      vec![0xFF, 0xFF],
+      vec![0xFF, 0xFF, 0xFF],
+    ];
+    let cursor = engine.new_transaction().unwrap();
+    for k in keys.iter() {
+      cursor.insert(k.clone(), k.clone()).unwrap();
+    }
+    cursor.commit().unwrap();
+
+    let cursor = engine.new_transaction().unwrap();
+    for prefix in [
+      vec![],
+      vec![0x61],
+      vec![0x61, 0xFF],
+      vec![0x63],
+      vec![0xFF],
+      vec![0xFF, 0xFF],
+    ] {
+      let found: Vec<Vec<u8>> = cursor
+        .scan_prefix(&prefix)
+        .unwrap()
+        .map(|r| r.unwrap().0)
+        .collect();
+      let want: Vec<Vec<u8>> = keys
+        .iter()
+        .filter(|k| k.starts_with(&prefix))
+        .cloned()
+        .collect();
+      assert_eq!(found, want, "{:?}", prefix);
+
+      let found: Vec<Vec<u8>> = cursor
+        .scan_prefix(&prefix)
+        .unwrap()
+        .rev()
+        .map(|r| r.unwrap().0)
+        .collect();
+      let want: Vec<Vec<u8>> = want.into_iter().rev().collect();
+      assert_eq!(found, want, "{:?}", prefix);
+    }
+    cursor.commit().unwrap();
+  }
+}
@@ -0,0 +1,197 @@
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  sync::{Condvar, Mutex},
+  time::{Duration, Instant},
+};
+
+use crate::{Error, Result, ShortenedMutex};
+
+type LockKey = (String, Vec<u8>);
+
+#[derive(Default)]
+struct KeyLocksCore {
+  owners: BTreeMap<LockKey, usize>,
+  owned: BTreeMap<usize, BTreeSet<LockKey>>,
+  waiting: BTreeMap<usize, usize>,
+  victims: BTreeSet<usize>,
+  /// the last commit of each key newer than the oldest snapshot.
+  versions: BTreeMap<LockKey, usize>,
+}
+impl KeyLocksCore {
+  /// Follows the wait-for graph from the owner,
+  /// returns the transactions in the cycle if it leads back to the waiter.
+  fn find_cycle(&self, tx_id: usize, owner: usize) -> Option<Vec<usize>> {
+    let mut cycle = vec![tx_id];
+    let mut current = owner;
+    while !cycle.contains(&current) {
+      cycle.push(current);
+      current = *self.waiting.get(&current)?;
+    }
+    current.eq(&tx_id).then_some(cycle)
+  }
+}
+
+/// Key locks, held by a transaction until it commits or aborts.
+/// They are only taken while the transaction holds no page locks,
+/// pages are locked at commit and never waited on while locked,
+/// so a page wait cannot close a cycle this graph misses.
+pub struct KeyLocks {
+  core: Mutex<KeyLocksCore>,
+  released: Condvar,
+  timeout: Duration,
+}
+impl KeyLocks {
+  pub fn new(timeout: Duration) -> Self {
+    Self {
+      core: Default::default(),
+      released: Condvar::new(),
+      timeout,
+    }
+  }
+
+  /// Waits for the owner to finish, aborting the youngest transaction
+  /// of the wait-for cycle if there is one.
+  pub fn acquire(&self, tx_id: usize, keyspace: &str, key: &[u8]) -> Result {
+    let lock_key = (keyspace.to_string(), key.to_vec());
+    let deadline = Instant::now() + self.timeout;
+    let mut core = self.core.l();
+    loop {
+      if core.victims.remove(&tx_id) {
+        core.waiting.remove(&tx_id);
+        return Err(Error::Deadlock);
+      }
+
+      let owner = match core.owners.get(&lock_key) {
+        None => {
+          core.waiting.remove(&tx_id);
+          core.owners.insert(lock_key.clone(), tx_id);
+          core.owned.entry(tx_id).or_default().insert(lock_key);
+          return Ok(());
+        }
+        Some(&owner) if owner.eq(&tx_id) => return Ok(()),
+        Some(&owner) => owner,
+      };
+
+      core.waiting.insert(tx_id, owner);
+      if let Some(cycle) = core.find_cycle(tx_id, owner) {
+        let victim = cycle.into_iter().max().unwrap();
+        if victim.eq(&tx_id) {
+          core.waiting.remove(&tx_id);
+          core.victims.remove(&tx_id);
+          return Err(Error::Deadlock);
+        }
+        core.victims.insert(victim);
+        self.released.notify_all();
+      }
+
+      let now = Instant::now();
+      if now.ge(&deadline) {
+        core.waiting.remove(&tx_id);
+        core.victims.remove(&tx_id);
+        return Err(Error::LockTimeout);
+      }
+      core = self.released.wait_timeout(core, deadline - now).unwrap().0;
+    }
+  }
+
+  /// Fails if the key was committed after the snapshot. The lock is held,
+  /// so no other commit of the key can come in between.
+  pub fn check_version(&self, keyspace: &str, key: &[u8], snapshot: usize) -> Result {
+    let lock_key = (keyspace.to_string(), key.to_vec());
+    match self.core.l().versions.get(&lock_key) {
+      Some(version) if version.gt(&snapshot) => Err(Error::Conflict),
+      _ => Ok(()),
+    }
+  }
+
+  /// Records the commit as the version of the written keys before releasing
+  /// the locks, and forgets the versions every snapshot already includes.
+  pub fn commit(
+    &self,
+    tx_id: usize,
+    commit_index: usize,
+    written: Vec<LockKey>,
+    oldest_snapshot: usize,
+  ) {
+    {
+      let mut core = self.core.l();
+      core.versions.retain(|_, v| (*v).gt(&oldest_snapshot));
+      for lock_key in written {
+        core.versions.insert(lock_key, commit_index);
+      }
+    }
+    self.release_all(tx_id);
+  }
+
+  pub fn release_all(&self, tx_id: usize) {
+    let mut core = self.core.l();
+    for lock_key in core.owned.remove(&tx_id).unwrap_or_default() {
+      core.owners.remove(&lock_key);
+    }
+    core.waiting.remove(&tx_id);
+    core.victims.remove(&tx_id);
+    self.released.notify_all();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    sync::{Arc, Barrier},
+    thread,
+    time::Duration,
+  };
+
+  use crate::Error;
+
+  use super::KeyLocks;
+
+  #[test]
+  fn _1() {
+    let locks = KeyLocks::new(Duration::from_millis(10));
+    let (a, b) = (b"a".to_vec(), b"b".to_vec());
+    locks.acquire(1, "default", &a).unwrap();
+    locks.acquire(1, "default", &a).unwrap();
+    locks.acquire(2, "other", &a).unwrap();
+    assert!(matches!(
+      locks.acquire(2, "default", &a),
+      Err(Error::LockTimeout)
+    ));
+    locks.release_all(1);
+    locks.acquire(2, "default", &a).unwrap();
+    locks.acquire(1, "default", &b).unwrap();
+
+    locks.check_version("default", &a, 0).unwrap();
+    locks.commit(2, 5, vec![("default".to_string(), a.clone())], 3);
+    assert!(matches!(
+      locks.check_version("default", &a, 4),
+      Err(Error::Conflict)
+    ));
+    locks.check_version("default", &a, 5).unwrap();
+    locks.acquire(3, "default", &a).unwrap();
+  }
+
+  #[test]
+  fn _2() {
+    let locks = Arc::new(KeyLocks::new(Duration::from_secs(5)));
+    let barrier = Arc::new(Barrier::new(2));
+    let (a, b) = (b"a".to_vec(), b"b".to_vec());
+    locks.acquire(1, "default", &a).unwrap();
+    locks.acquire(2, "default", &b).unwrap();
+
+    let (l, start) = (locks.clone(), barrier.clone());
+    let younger = thread::spawn(move || {
+      start.wait();
+      l.acquire(2, "default", &a)
+    });
+    let (l, start) = (locks.clone(), barrier.clone());
+    let older = thread::spawn(move || {
+      start.wait();
+      l.acquire(1, "default", &b)
+    });
+
+    assert!(matches!(younger.join().unwrap(), Err(Error::Deadlock)));
+    locks.release_all(2);
+    older.join().unwrap().unwrap();
+  }
+}
@@ -1,11 +1,41 @@
 mod header;
+pub use header::DEFAULT_KEYSPACE;
 use header::*;
 
 mod entry;
+pub use entry::MAX_KEY_SIZE;
 use entry::*;
 
+mod value;
+use value::*;
+
+mod reader;
+use reader::*;
+
+mod pending;
+use pending::*;
+
 mod writer;
+pub use writer::Savepoint;
 use writer::*;
 
+mod iter;
+pub use iter::*;
+
+mod options;
+pub use options::*;
+
+mod lock;
+pub use lock::*;
+
+mod ssi;
+pub use ssi::*;
+
+mod defrag;
+use defrag::*;
+
 mod cursor;
 pub use cursor::*;
+
+mod read;
+pub use read::*;
@@ -0,0 +1,90 @@
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationLevel {
+  /// Every operation reads the latest committed state.
+  ReadCommitted,
+  /// Every operation reads the state committed before the transaction started.
+  #[default]
+  Snapshot,
+  /// Snapshot reads, aborting transactions that could not have run one after another.
+  Serializable,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TransactionOptions {
+  pub isolation: IsolationLevel,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{IsolationLevel, TransactionOptions};
+  use crate::{engine::TestEngine, Error};
+
+  fn update(engine: &TestEngine, value: u8) {
+    let cursor = engine.new_transaction().unwrap();
+    cursor.insert(b"key".to_vec(), vec![value]).unwrap();
+    cursor.commit().unwrap();
+  }
+
+  fn begin(engine: &TestEngine, isolation: IsolationLevel) -> crate::Cursor {
+    engine
+      .new_transaction_with(TransactionOptions { isolation })
+      .unwrap()
+  }
+
+  #[test]
+  fn _1() {
+    let engine = TestEngine::open("options-1");
+    update(&engine, 1);
+
+    let read_committed = begin(&engine, IsolationLevel::ReadCommitted);
+    let snapshot = begin(&engine, IsolationLevel::Snapshot);
+    assert_eq!(read_committed.get(&b"key".to_vec()).unwrap(), vec![1]);
+    assert_eq!(snapshot.get(&b"key".to_vec()).unwrap(), vec![1]);
+
+    update(&engine, 2);
+    let cursor = engine.new_transaction().unwrap();
+    cursor.insert(b"new".to_vec(), vec![3]).unwrap();
+    cursor.commit().unwrap();
+
+    assert_eq!(read_committed.get(&b"key".to_vec()).unwrap(), vec![2]);
+    assert_eq!(read_committed.get(&b"new".to_vec()).unwrap(), vec![3]);
+    assert_eq!(read_committed.range(..).unwrap().count(), 2);
+    assert_eq!(snapshot.get(&b"key".to_vec()).unwrap(), vec![1]);
+    assert!(matches!(
+      snapshot.get(&b"new".to_vec()),
+      Err(Error::NotFound)
+    ));
+    assert_eq!(snapshot.range(..).unwrap().count(), 1);
+    read_committed.commit().unwrap();
+    snapshot.commit().unwrap();
+  }
+
+  /// An iterator stays at the snapshot it started at
+  /// while the read committed cursor moves on, and keeps it pinned.
+  #[test]
+  fn _2() {
+    let engine = TestEngine::open("options-2");
+    let key = |i: usize| format!("key{:04}", i).into_bytes();
+    let fill = |value: u8| {
+      let cursor = engine.new_transaction().unwrap();
+      for i in 0..300 {
+        cursor.insert(key(i), vec![value; 40]).unwrap();
+      }
+      cursor.commit().unwrap();
+    };
+    fill(1);
+
+    let snapshot = engine.read_transaction().unwrap().get_snapshot();
+    let read_committed = begin(&engine, IsolationLevel::ReadCommitted);
+    let mut iter = read_committed.range(..).unwrap();
+    assert_eq!(iter.next().unwrap().unwrap().1, vec![1; 40]);
+
+    fill(2);
+    assert_eq!(read_committed.get(&key(0)).unwrap(), vec![2; 40]);
+    assert!(engine.oldest_snapshot().le(&snapshot));
+    assert!(iter.all(|e| e.unwrap().1.eq(&vec![1; 40])));
+    drop(iter);
+    assert!(engine.oldest_snapshot().gt(&snapshot));
+    read_committed.commit().unwrap();
+  }
+}
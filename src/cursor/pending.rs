@@ -0,0 +1,161 @@
+use std::{
+  collections::BTreeMap,
+  ops::{Add, AddAssign, Bound, RangeBounds},
+};
+
+use crate::{Error, Result};
+
+type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+type Undo = (String, Vec<u8>, Option<Option<Vec<u8>>>);
+
+/// How far the writes went when the savepoint was taken.
+#[derive(Clone, Copy)]
+pub struct PendingSavepoint {
+  id: usize,
+  writes: usize,
+  keyspaces: usize,
+}
+
+/// Writes of a transaction, kept out of the tree until its commit applies them.
+/// None is a deletion. Overwritten entries are remembered for savepoints.
+#[derive(Default)]
+pub struct PendingWrites {
+  writes: BTreeMap<String, BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+  undo: Vec<Undo>,
+  keyspaces: Vec<String>,
+  /// ids of the savepoints no rollback went past, in the order they were taken.
+  savepoints: Vec<usize>,
+  next_savepoint: usize,
+}
+impl PendingWrites {
+  pub fn get(&self, keyspace: &str, key: &Vec<u8>) -> Option<Option<Vec<u8>>> {
+    self.writes.get(keyspace)?.get(key).cloned()
+  }
+
+  pub fn insert(&mut self, keyspace: &str, key: Vec<u8>, value: Option<Vec<u8>>) {
+    let previous = self
+      .writes
+      .entry(keyspace.to_string())
+      .or_default()
+      .insert(key.clone(), value);
+    self.undo.push((keyspace.to_string(), key, previous));
+  }
+
+  /// The entries of the keyspace inside the range, in key order.
+  pub fn range(
+    &self,
+    keyspace: &str,
+    range: &KeyRange,
+  ) -> BTreeMap<Vec<u8>, Option<Vec<u8>>> {
+    self
+      .writes
+      .get(keyspace)
+      .map(|writes| {
+        writes
+          .iter()
+          .filter(|(key, _)| range.contains(*key))
+          .map(|(key, value)| (key.clone(), value.clone()))
+          .collect()
+      })
+      .unwrap_or_default()
+  }
+
+  pub fn entries(&self) -> Vec<(String, Vec<u8>, Option<Vec<u8>>)> {
+    self
+      .writes
+      .iter()
+      .flat_map(|(keyspace, writes)| {
+        writes
+          .iter()
+          .map(|(key, value)| (keyspace.clone(), key.clone(), value.clone()))
+      })
+      .collect()
+  }
+
+  pub fn create_keyspace(&mut self, name: &str) {
+    self.keyspaces.push(name.to_string());
+  }
+
+  pub fn has_keyspace(&self, name: &str) -> bool {
+    self.keyspaces.iter().any(|k| k.eq(name))
+  }
+
+  pub fn keyspaces(&self) -> Vec<String> {
+    self.keyspaces.clone()
+  }
+
+  pub fn savepoint(&mut self) -> PendingSavepoint {
+    let id = self.next_savepoint;
+    self.next_savepoint.add_assign(1);
+    self.savepoints.push(id);
+    PendingSavepoint {
+      id,
+      writes: self.undo.len(),
+      keyspaces: self.keyspaces.len(),
+    }
+  }
+
+  /// Savepoints taken after the target are dropped, so rolling back
+  /// to one of them later fails even if the counts would allow it.
+  pub fn rollback_to(&mut self, savepoint: PendingSavepoint) -> Result {
+    let position = match self.savepoints.binary_search(&savepoint.id) {
+      Ok(position) => position,
+      Err(_) => return Err(Error::Invalid),
+    };
+    self.savepoints.truncate(position.add(1));
+    let PendingSavepoint {
+      writes, keyspaces, ..
+    } = savepoint;
+    for (keyspace, key, previous) in self.undo.split_off(writes).into_iter().rev() {
+      let writes = self.writes.entry(keyspace).or_default();
+      match previous {
+        Some(value) => writes.insert(key, value),
+        None => writes.remove(&key),
+      };
+    }
+    self.keyspaces.truncate(keyspaces);
+    Ok(())
+  }
+
+  pub fn clear(&mut self) {
+    *self = Default::default();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::ops::Bound;
+
+  use super::PendingWrites;
+
+  #[test]
+  fn _1() {
+    let mut pending = PendingWrites::default();
+    let (a, b) = (b"a".to_vec(), b"b".to_vec());
+    pending.insert("default", a.clone(), Some(vec![1]));
+    let savepoint = pending.savepoint();
+    pending.insert("default", a.clone(), None);
+    pending.insert("default", b.clone(), Some(vec![2]));
+    pending.create_keyspace("other");
+    assert_eq!(pending.get("default", &a), Some(None));
+    assert!(pending.has_keyspace("other"));
+
+    let range = (Bound::Excluded(a.clone()), Bound::Unbounded);
+    assert_eq!(pending.range("default", &range).len(), 1);
+
+    let stale = pending.savepoint();
+    pending.rollback_to(savepoint).unwrap();
+    assert_eq!(pending.get("default", &a), Some(Some(vec![1])));
+    assert_eq!(pending.get("default", &b), None);
+    assert!(!pending.has_keyspace("other"));
+
+    // the counts fit again, but the savepoint was rolled back past.
+    for _ in 0..3 {
+      pending.insert("default", b.clone(), Some(vec![3]));
+    }
+    pending.create_keyspace("other");
+    assert!(pending.rollback_to(stale).is_err());
+    pending.rollback_to(savepoint).unwrap();
+    assert_eq!(pending.get("default", &b), None);
+  }
+}
@@ -0,0 +1,170 @@
+use std::{ops::RangeBounds, sync::Arc};
+
+use crate::{buffer::BufferPool, logger, Result};
+
+use super::{CursorIterator, CursorReader, DEFAULT_KEYSPACE};
+
+/// No transaction ever gets this id, so only committed versions are visible.
+const READ_ONLY_TX_ID: usize = 0;
+
+/// A read-only view of the state at a commit index,
+/// which neither takes a transaction id nor writes to the log.
+pub struct ReadCursor {
+  reader: CursorReader,
+  buffer: Arc<BufferPool>,
+}
+impl ReadCursor {
+  /// The snapshot has to be pinned already, it is unpinned on drop.
+  pub fn new(buffer: Arc<BufferPool>, snapshot: usize) -> Self {
+    logger::info(format!("read cursor at lsn {} init", snapshot));
+    Self {
+      reader: CursorReader::new(READ_ONLY_TX_ID, snapshot, buffer.clone()),
+      buffer,
+    }
+  }
+
+  pub fn get_snapshot(&self) -> usize {
+    self.reader.get_snapshot()
+  }
+
+  pub fn keyspace(&self, name: &str) -> Result<ReadKeyspace<'_>> {
+    self.reader.get_root(name)?;
+    Ok(ReadKeyspace {
+      cursor: self,
+      name: name.to_string(),
+    })
+  }
+
+  pub fn get(&self, key: &Vec<u8>) -> Result<Vec<u8>> {
+    self.get_in(DEFAULT_KEYSPACE, key)
+  }
+
+  pub fn range<R>(&self, range: R) -> Result<CursorIterator<'_>>
+  where
+    R: RangeBounds<Vec<u8>>,
+  {
+    self.range_in(DEFAULT_KEYSPACE, range)
+  }
+
+  pub fn scan_prefix(&self, prefix: &[u8]) -> Result<CursorIterator<'_>> {
+    self.scan_prefix_in(DEFAULT_KEYSPACE, prefix)
+  }
+
+  fn get_in(&self, keyspace: &str, key: &Vec<u8>) -> Result<Vec<u8>> {
+    self
+      .reader
+      .get_leaf_value(self.reader.get_index(keyspace, key)?)
+  }
+
+  fn range_in<R>(&self, keyspace: &str, range: R) -> Result<CursorIterator<'_>>
+  where
+    R: RangeBounds<Vec<u8>>,
+  {
+    Ok(CursorIterator::new(
+      &self.reader,
+      Some(self.reader.get_root(keyspace)?),
+      range.start_bound().cloned(),
+      range.end_bound().cloned(),
+    ))
+  }
+
+  fn scan_prefix_in(&self, keyspace: &str, prefix: &[u8]) -> Result<CursorIterator<'_>> {
+    Ok(CursorIterator::prefix(
+      &self.reader,
+      Some(self.reader.get_root(keyspace)?),
+      prefix,
+    ))
+  }
+}
+
+impl Drop for ReadCursor {
+  fn drop(&mut self) {
+    self.buffer.unpin_snapshot(self.reader.get_snapshot());
+  }
+}
+
+pub struct ReadKeyspace<'a> {
+  cursor: &'a ReadCursor,
+  name: String,
+}
+impl<'a> ReadKeyspace<'a> {
+  pub fn get(&self, key: &Vec<u8>) -> Result<Vec<u8>> {
+    self.cursor.get_in(&self.name, key)
+  }
+
+  pub fn range<R>(&self, range: R) -> Result<CursorIterator<'a>>
+  where
+    R: RangeBounds<Vec<u8>>,
+  {
+    self.cursor.range_in(&self.name, range)
+  }
+
+  pub fn scan_prefix(&self, prefix: &[u8]) -> Result<CursorIterator<'a>> {
+    self.cursor.scan_prefix_in(&self.name, prefix)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{engine::TestEngine, Error};
+
+  fn key(i: usize) -> Vec<u8> {
+    format!("key{:04}", i).into_bytes()
+  }
+
+  #[test]
+  fn _1() {
+    let engine = TestEngine::open("read-1");
+    let cursor = engine.new_transaction().unwrap();
+    for i in 0..200 {
+      cursor.insert(key(i), vec![1; 40]).unwrap();
+    }
+    cursor.commit().unwrap();
+
+    let read = engine.read_transaction().unwrap();
+    let cursor = engine.new_transaction().unwrap();
+    for i in 0..200 {
+      cursor.insert(key(i), vec![2; 40]).unwrap();
+    }
+    cursor.insert(key(500), vec![2; 40]).unwrap();
+    cursor.commit().unwrap();
+
+    assert_eq!(read.get(&key(10)).unwrap(), vec![1; 40]);
+    assert!(matches!(read.get(&key(500)), Err(Error::NotFound)));
+    let found: Vec<_> = read.range(key(50)..key(150)).unwrap().collect();
+    assert_eq!(found.len(), 100);
+    for (i, entry) in found.into_iter().enumerate() {
+      assert_eq!(entry.unwrap(), (key(i + 50), vec![1; 40]));
+    }
+    assert_eq!(read.scan_prefix(b"key01").unwrap().rev().count(), 100);
+
+    let fresh = engine.read_transaction().unwrap();
+    assert!(fresh.get_snapshot().gt(&read.get_snapshot()));
+    assert_eq!(fresh.get(&key(10)).unwrap(), vec![2; 40]);
+    assert_eq!(fresh.get(&key(500)).unwrap(), vec![2; 40]);
+  }
+
+  #[test]
+  fn _2() {
+    let engine = TestEngine::open("read-2");
+    engine.create_keyspace("other").unwrap();
+    let cursor = engine.new_transaction().unwrap();
+    cursor
+      .keyspace("other")
+      .unwrap()
+      .insert(key(1), vec![3])
+      .unwrap();
+    cursor.commit().unwrap();
+
+    let read = engine.read_transaction().unwrap();
+    let other = read.keyspace("other").unwrap();
+    assert_eq!(other.get(&key(1)).unwrap(), vec![3]);
+    assert_eq!(other.range(..).unwrap().count(), 1);
+    assert_eq!(other.scan_prefix(b"key").unwrap().count(), 1);
+    assert!(matches!(read.get(&key(1)), Err(Error::NotFound)));
+    assert!(matches!(
+      read.keyspace("missing"),
+      Err(Error::KeyspaceNotFound)
+    ));
+  }
+}
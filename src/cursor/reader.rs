@@ -0,0 +1,84 @@
+use std::sync::{
+  atomic::{AtomicUsize, Ordering},
+  Arc,
+};
+
+use crate::{buffer::BufferPool, Error, Page, Result};
+
+use super::{CursorEntry, LeafValue, TreeHeader, ValuePage, HEADER_INDEX};
+
+/// Reads pages as of a snapshot, plus the transaction's own writes.
+pub struct CursorReader {
+  tx_id: usize,
+  snapshot: AtomicUsize,
+  buffer: Arc<BufferPool>,
+}
+impl CursorReader {
+  pub fn new(tx_id: usize, snapshot: usize, buffer: Arc<BufferPool>) -> Self {
+    Self {
+      tx_id,
+      snapshot: AtomicUsize::new(snapshot),
+      buffer,
+    }
+  }
+
+  pub fn get_snapshot(&self) -> usize {
+    self.snapshot.load(Ordering::SeqCst)
+  }
+
+  pub fn set_snapshot(&self, snapshot: usize) {
+    self.snapshot.store(snapshot, Ordering::SeqCst);
+  }
+
+  /// Another reader at the current snapshot, which stays there while this one
+  /// moves on. The snapshot is pinned for it until it is unpinned.
+  pub fn pin(&self) -> CursorReader {
+    let snapshot = self.buffer.pin_snapshot(|| self.get_snapshot());
+    Self::new(self.tx_id, snapshot, self.buffer.clone())
+  }
+
+  pub fn unpin(&self) {
+    self.buffer.unpin_snapshot(self.get_snapshot())
+  }
+
+  pub fn get(&self, index: usize) -> Result<Page> {
+    self.buffer.get(self.tx_id, self.get_snapshot(), index)
+  }
+
+  pub fn get_root(&self, keyspace: &str) -> Result<usize> {
+    let header: TreeHeader = self.get(HEADER_INDEX)?.deserialize()?;
+    header.get_root(keyspace)
+  }
+
+  pub fn get_index(&self, keyspace: &str, key: &Vec<u8>) -> Result<LeafValue> {
+    let mut index = self.get_root(keyspace)?;
+    loop {
+      let entry: CursorEntry = self.get(index)?.deserialize()?;
+      match entry.find_or_next(key) {
+        Ok(i) => return Ok(i),
+        Err(n) => match n {
+          Some(i) => index = i,
+          None => return Err(Error::NotFound),
+        },
+      }
+    }
+  }
+
+  pub fn get_value(&self, index: usize) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    let mut current = Some(index);
+    while let Some(i) = current {
+      let mut value: ValuePage = self.get(i)?.deserialize()?;
+      data.append(&mut value.data);
+      current = value.next;
+    }
+    Ok(data)
+  }
+
+  pub fn get_leaf_value(&self, value: LeafValue) -> Result<Vec<u8>> {
+    match value {
+      LeafValue::Inline(data) => Ok(data),
+      LeafValue::Page(index) => self.get_value(index),
+    }
+  }
+}
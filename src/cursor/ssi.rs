@@ -0,0 +1,239 @@
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  ops::{Bound, RangeBounds},
+  sync::Mutex,
+};
+
+use crate::{Error, Result, ShortenedMutex};
+
+type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+struct TrackedTransaction {
+  snapshot: usize,
+  committed: Option<usize>,
+  reads: Vec<(String, KeyRange)>,
+  writes: BTreeSet<(String, Vec<u8>)>,
+  inbound: bool,
+  outbound: bool,
+  doomed: bool,
+}
+impl TrackedTransaction {
+  fn new(snapshot: usize) -> Self {
+    Self {
+      snapshot,
+      committed: None,
+      reads: Default::default(),
+      writes: Default::default(),
+      inbound: false,
+      outbound: false,
+      doomed: false,
+    }
+  }
+
+  fn is_active(&self) -> bool {
+    self.committed.map(|c| c.eq(&usize::MAX)).unwrap_or(true)
+  }
+
+  /// Neither of them could see the other's writes.
+  fn is_concurrent(&self, other: &Self) -> bool {
+    let before =
+      |a: &Self, b: &Self| a.committed.map(|c| c.le(&b.snapshot)).unwrap_or(false);
+    !before(self, other) && !before(other, self)
+  }
+
+  fn has_read(&self, keyspace: &str, key: &Vec<u8>) -> bool {
+    self
+      .reads
+      .iter()
+      .any(|(name, range)| name.eq(keyspace) && range.contains(key))
+  }
+}
+
+/// Tracks rw-antidependencies between serializable transactions.
+/// A transaction with both an inbound and an outbound one is the pivot of
+/// a dangerous structure, and it or the one completing it has to be aborted.
+#[derive(Default)]
+pub struct SerializableTracker {
+  transactions: Mutex<BTreeMap<usize, TrackedTransaction>>,
+}
+impl SerializableTracker {
+  /// Takes the snapshot under the tracker lock,
+  /// so that no overlapping transaction is forgotten before it is registered.
+  pub fn begin<F>(&self, tx_id: usize, snapshot: F) -> usize
+  where
+    F: FnOnce() -> usize,
+  {
+    let mut transactions = self.transactions.l();
+    let snapshot = snapshot();
+    transactions.insert(tx_id, TrackedTransaction::new(snapshot));
+    snapshot
+  }
+
+  pub fn read(&self, tx_id: usize, keyspace: &str, range: KeyRange) -> Result {
+    let mut transactions = self.transactions.l();
+    let reader = match transactions.get(&tx_id) {
+      Some(t) => t,
+      None => return Ok(()),
+    };
+    let writers: Vec<usize> = transactions
+      .iter()
+      .filter(|(&id, t)| id.ne(&tx_id) && t.is_concurrent(reader))
+      .filter(|(_, t)| {
+        t.writes
+          .iter()
+          .any(|(name, key)| name.eq(keyspace) && range.contains(key))
+      })
+      .map(|(&id, _)| id)
+      .collect();
+
+    transactions
+      .get_mut(&tx_id)
+      .unwrap()
+      .reads
+      .push((keyspace.to_string(), range));
+    for writer in writers {
+      Self::add_edge(&mut transactions, tx_id, tx_id, writer)?;
+    }
+    Ok(())
+  }
+
+  pub fn write(&self, tx_id: usize, keyspace: &str, key: &Vec<u8>) -> Result {
+    let mut transactions = self.transactions.l();
+    let writer = match transactions.get(&tx_id) {
+      Some(t) => t,
+      None => return Ok(()),
+    };
+    let readers: Vec<usize> = transactions
+      .iter()
+      .filter(|(&id, t)| id.ne(&tx_id) && t.is_concurrent(writer))
+      .filter(|(_, t)| t.has_read(keyspace, key))
+      .map(|(&id, _)| id)
+      .collect();
+
+    transactions
+      .get_mut(&tx_id)
+      .unwrap()
+      .writes
+      .insert((keyspace.to_string(), key.clone()));
+    for reader in readers {
+      Self::add_edge(&mut transactions, tx_id, reader, tx_id)?;
+    }
+    Ok(())
+  }
+
+  fn add_edge(
+    transactions: &mut BTreeMap<usize, TrackedTransaction>,
+    current: usize,
+    reader: usize,
+    writer: usize,
+  ) -> Result {
+    if let Some(t) = transactions.get_mut(&reader) {
+      t.outbound = true;
+    }
+    if let Some(t) = transactions.get_mut(&writer) {
+      t.inbound = true;
+    }
+
+    // aborting the current transaction is enough to break the structure.
+    let other = if current.eq(&reader) { writer } else { reader };
+    for pivot in [current, other] {
+      let t = match transactions.get_mut(&pivot) {
+        Some(t) => t,
+        None => continue,
+      };
+      if !t.inbound || !t.outbound {
+        continue;
+      }
+      if pivot.eq(&current) {
+        t.doomed = true;
+        return Err(Error::SerializationFailure);
+      }
+      if t.committed.is_some() {
+        transactions.get_mut(&current).unwrap().doomed = true;
+        return Err(Error::SerializationFailure);
+      }
+      t.doomed = true;
+    }
+    Ok(())
+  }
+
+  /// Fails if the transaction became a pivot, otherwise it can no longer be
+  /// chosen as a victim and others will be aborted in its place.
+  pub fn prepare(&self, tx_id: usize) -> Result {
+    let mut transactions = self.transactions.l();
+    let t = match transactions.get_mut(&tx_id) {
+      Some(t) => t,
+      None => return Ok(()),
+    };
+    if t.doomed {
+      return Err(Error::SerializationFailure);
+    }
+    t.committed = Some(usize::MAX);
+    Ok(())
+  }
+
+  pub fn commit(&self, tx_id: usize, commit_index: usize) {
+    let mut transactions = self.transactions.l();
+    if let Some(t) = transactions.get_mut(&tx_id) {
+      t.committed = Some(commit_index);
+    }
+    Self::collect(&mut transactions);
+  }
+
+  pub fn abort(&self, tx_id: usize) {
+    let mut transactions = self.transactions.l();
+    if transactions.remove(&tx_id).is_some() {
+      Self::collect(&mut transactions);
+    }
+  }
+
+  /// Forgets committed transactions that no active one overlaps with.
+  fn collect(transactions: &mut BTreeMap<usize, TrackedTransaction>) {
+    let oldest = transactions
+      .values()
+      .filter(|t| t.is_active())
+      .map(|t| t.snapshot)
+      .min()
+      .unwrap_or(usize::MAX);
+    transactions.retain(|_, t| t.is_active() || t.committed.unwrap().gt(&oldest));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::ops::Bound;
+
+  use crate::Error;
+
+  use super::SerializableTracker;
+
+  #[test]
+  fn _1() {
+    let tracker = SerializableTracker::default();
+    let point = |k: &[u8]| (Bound::Included(k.to_vec()), Bound::Included(k.to_vec()));
+    tracker.begin(1, || 10);
+    tracker.begin(2, || 10);
+    tracker.read(1, "default", point(b"x")).unwrap();
+    tracker.read(1, "default", point(b"y")).unwrap();
+    tracker.read(2, "default", point(b"x")).unwrap();
+    tracker.read(2, "default", point(b"y")).unwrap();
+
+    tracker.write(1, "default", &b"x".to_vec()).unwrap();
+    assert!(matches!(
+      tracker.write(2, "default", &b"y".to_vec()),
+      Err(Error::SerializationFailure)
+    ));
+    assert!(matches!(
+      tracker.prepare(2),
+      Err(Error::SerializationFailure)
+    ));
+    tracker.prepare(1).unwrap();
+    tracker.commit(1, 11);
+    tracker.abort(2);
+
+    tracker.begin(3, || 11);
+    tracker.read(3, "default", point(b"x")).unwrap();
+    tracker.write(3, "default", &b"y".to_vec()).unwrap();
+    tracker.prepare(3).unwrap();
+  }
+}
@@ -0,0 +1,76 @@
+use crate::{
+  disk::{Page, Serializable},
+  error::Error,
+  PAGE_SIZE,
+};
+
+pub const VALUE_PAGE_CAPACITY: usize = PAGE_SIZE - 18;
+
+#[derive(Debug)]
+pub struct ValuePage {
+  pub data: Vec<u8>,
+  pub next: Option<usize>,
+}
+impl ValuePage {
+  pub fn new(data: Vec<u8>, next: Option<usize>) -> Self {
+    Self { data, next }
+  }
+
+  pub fn chunks(value: &[u8]) -> Vec<Vec<u8>> {
+    if value.is_empty() {
+      return vec![vec![]];
+    }
+    value
+      .chunks(VALUE_PAGE_CAPACITY)
+      .map(|c| c.to_vec())
+      .collect()
+  }
+}
+
+impl Serializable for ValuePage {
+  fn serialize(&self) -> Result<Page, Error> {
+    let mut p = Page::new();
+    let mut wt = p.writer();
+    let next = self.next.unwrap_or(0);
+    wt.write(&next.to_be_bytes())?;
+    wt.write(&self.data.len().to_be_bytes())?;
+    wt.write(self.data.as_ref())?;
+    Ok(p)
+  }
+
+  fn deserialize(value: &Page) -> Result<Self, Error> {
+    let mut sc = value.scanner();
+    let next = sc.read_usize()?;
+    let next = if next.eq(&0) { None } else { Some(next) };
+    let len = sc.read_usize()?;
+    let data = sc.read_n(len)?.to_vec();
+    Ok(Self { data, next })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::Serializable;
+
+  use super::{ValuePage, VALUE_PAGE_CAPACITY};
+
+  #[test]
+  fn _1() {
+    for data in [vec![], vec![0], vec![1, 2, 3, 0, 0]] {
+      let page = ValuePage::new(data.clone(), Some(3)).serialize().unwrap();
+      let value: ValuePage = page.deserialize().unwrap();
+      assert_eq!(value.data, data);
+      assert_eq!(value.next, Some(3));
+    }
+  }
+
+  #[test]
+  fn _2() {
+    let data = vec![7; VALUE_PAGE_CAPACITY * 2 + 1];
+    let chunks = ValuePage::chunks(&data);
+    assert_eq!(chunks.len(), 3);
+    for chunk in chunks {
+      ValuePage::new(chunk, None).serialize().unwrap();
+    }
+  }
+}
@@ -1,33 +1,66 @@
-use std::sync::Arc;
+use std::{
+  collections::BTreeSet,
+  sync::{Arc, Mutex, MutexGuard},
+};
 
 use crate::{
   buffer::{BufferPool, BLOCK_SIZE},
   disk::FreeList,
   wal::WriteAheadLog,
-  Page, Result,
+  DrainAll, Error, Page, Result, Serializable, ShortenedMutex,
+};
+
+use super::{
+  CursorReader, IsolationLevel, LeafValue, PendingSavepoint, PendingWrites, ValuePage,
+  MAX_INLINE_VALUE_SIZE,
 };
 
+pub struct Savepoint {
+  tx_id: usize,
+  pending: PendingSavepoint,
+  writes: usize,
+  records: usize,
+  allocated: usize,
+  released: usize,
+}
+
 pub struct CursorWriter {
   tx_id: usize,
-  last_commit_index: usize,
+  reader: CursorReader,
+  isolation: IsolationLevel,
   wal: Arc<WriteAheadLog>,
   buffer: Arc<BufferPool>,
   freelist: Arc<FreeList<BLOCK_SIZE>>,
+  allocated: Mutex<Vec<usize>>,
+  released: Mutex<Vec<usize>>,
+  locked: Mutex<BTreeSet<usize>>,
+  written: Mutex<BTreeSet<usize>>,
+  pending: Mutex<PendingWrites>,
+  /// pages written while applying, logged once the whole attempt went through.
+  applying: Mutex<Option<BTreeSet<usize>>>,
 }
 impl CursorWriter {
   pub fn new(
     tx_id: usize,
     last_commit_index: usize,
+    isolation: IsolationLevel,
     wal: Arc<WriteAheadLog>,
     buffer: Arc<BufferPool>,
     freelist: Arc<FreeList<BLOCK_SIZE>>,
   ) -> Self {
     Self {
       tx_id,
-      last_commit_index,
+      reader: CursorReader::new(tx_id, last_commit_index, buffer.clone()),
+      isolation,
       wal,
       buffer,
       freelist,
+      allocated: Default::default(),
+      released: Default::default(),
+      locked: Default::default(),
+      written: Default::default(),
+      pending: Default::default(),
+      applying: Default::default(),
     }
   }
 
@@ -35,27 +68,363 @@ impl CursorWriter {
     self.tx_id
   }
 
+  pub fn get_isolation(&self) -> IsolationLevel {
+    self.isolation
+  }
+
+  /// Moves the snapshot to the latest commit under read committed.
+  pub fn refresh(&self) {
+    if let IsolationLevel::ReadCommitted = self.isolation {
+      let snapshot = self.buffer.pin_snapshot(|| self.wal.last_commit_index());
+      self.buffer.unpin_snapshot(self.reader.get_snapshot());
+      self.reader.set_snapshot(snapshot);
+    }
+  }
+
+  pub fn oldest_snapshot(&self) -> usize {
+    self.buffer.oldest_snapshot()
+  }
+
+  pub fn reader(&self) -> &CursorReader {
+    &self.reader
+  }
+
+  pub fn pending(&self) -> MutexGuard<'_, PendingWrites> {
+    self.pending.l()
+  }
+
   pub fn get(&self, index: usize) -> Result<Page> {
-    self.buffer.get(self.last_commit_index, index)
+    self.reader.get(index)
+  }
+
+  /// The latest committed page, or the one this transaction wrote.
+  pub fn get_latest(&self, index: usize) -> Result<Page> {
+    self.buffer.get_latest(self.tx_id, index)
+  }
+
+  /// Locks the page until the end of the transaction,
+  /// or the end of the operation if it is left unchanged.
+  pub fn get_for_update(&self, index: usize) -> Result<Page> {
+    self.lock(index)?;
+    self.buffer.get_latest(self.tx_id, index)
+  }
+
+  /// Fails with PageLocked instead of waiting while applying.
+  pub fn lock(&self, index: usize) -> Result {
+    let applying = self.applying.l().is_some();
+    match applying {
+      true => self.buffer.try_lock(self.tx_id, index)?,
+      false => self.buffer.lock(self.tx_id, index)?,
+    };
+    self.locked.l().insert(index);
+    Ok(())
+  }
+
+  pub fn unlock(&self, index: usize) {
+    let written = self.written.l();
+    if self.locked.l().remove(&index) && !written.contains(&index) {
+      self.buffer.unlock(self.tx_id, index);
+    }
+  }
+
+  pub fn unlock_unchanged(&self) {
+    let written = self.written.l();
+    for index in self.locked.l().drain_all() {
+      if !written.contains(&index) {
+        self.buffer.unlock(self.tx_id, index);
+      }
+    }
+  }
+
+  pub fn get_leaf_value(&self, value: LeafValue) -> Result<Vec<u8>> {
+    self.reader.get_leaf_value(value)
+  }
+
+  pub fn insert_leaf_value(&self, value: Vec<u8>) -> Result<LeafValue> {
+    if value.len().le(&MAX_INLINE_VALUE_SIZE) {
+      return Ok(LeafValue::Inline(value));
+    }
+    self.insert_value(value).map(LeafValue::Page)
+  }
+
+  pub fn insert_value(&self, value: Vec<u8>) -> Result<usize> {
+    let mut next = None;
+    for chunk in ValuePage::chunks(&value).into_iter().rev() {
+      let index = self.insert(ValuePage::new(chunk, next).serialize()?)?;
+      next = Some(index);
+    }
+    Ok(next.unwrap())
+  }
+
+  pub fn update_value(&self, index: usize, value: Vec<u8>) -> Result {
+    let mut chain: Vec<usize> =
+      self.get_chain(index)?.into_iter().map(|(i, _)| i).collect();
+    let chunks = ValuePage::chunks(&value);
+    let released = chain.split_off(chunks.len().min(chain.len()));
+
+    let mut next = None;
+    for (i, chunk) in chunks.into_iter().enumerate().rev() {
+      let page = ValuePage::new(chunk, next).serialize()?;
+      next = match chain.get(i) {
+        Some(&ci) => {
+          self.update(ci, page)?;
+          Some(ci)
+        }
+        None => Some(self.insert(page)?),
+      };
+    }
+
+    for i in released {
+      self.release(i)?;
+    }
+    Ok(())
+  }
+
+  pub fn release_value(&self, index: usize) -> Result {
+    for (i, _) in self.get_chain(index)? {
+      self.release(i)?;
+    }
+    Ok(())
+  }
+
+  fn get_chain(&self, index: usize) -> Result<Vec<(usize, ValuePage)>> {
+    let mut chain = vec![];
+    let mut current = Some(index);
+    while let Some(i) = current {
+      let value: ValuePage = self.get_for_update(i)?.deserialize()?;
+      current = value.next;
+      chain.push((i, value));
+    }
+    Ok(chain)
   }
 
   pub fn update(&self, index: usize, page: Page) -> Result {
+    self.lock(index)?;
     self.buffer.insert(self.tx_id, index, page.copy())?;
+    self.written.l().insert(index);
+    self.append(index, page)
+  }
+
+  fn append(&self, index: usize, page: Page) -> Result {
+    if let Some(applied) = self.applying.l().as_mut() {
+      applied.insert(index);
+      return Ok(());
+    }
     self.wal.append(self.tx_id, index, page)
   }
 
+  pub fn reserve(&self, index: usize) {
+    self.freelist.remove(index);
+  }
+
   pub fn insert(&self, page: Page) -> Result<usize> {
-    let index = self.freelist.acquire();
-    self.buffer.insert(self.tx_id, index, page.copy())?;
-    self.wal.append(self.tx_id, index, page)?;
+    let index = self.freelist.acquire(self.tx_id);
+    self.insert_at(index, page)?;
     Ok(index)
   }
 
-  pub fn commit(&self) -> Result {
-    self.wal.commit(self.tx_id)
+  pub fn insert_below(&self, page: Page, limit: usize) -> Result<Option<usize>> {
+    let index = match self.freelist.acquire_below(self.tx_id, limit) {
+      Some(i) => i,
+      None => return Ok(None),
+    };
+    self.insert_at(index, page)?;
+    Ok(Some(index))
+  }
+
+  fn insert_at(&self, index: usize, page: Page) -> Result {
+    self.allocated.l().push(index);
+    self.lock(index)?;
+    self.buffer.insert(self.tx_id, index, page.copy())?;
+    self.written.l().insert(index);
+    self.append(index, page)
+  }
+
+  /// Runs the tree changes of the commit. A page held by another transaction
+  /// undoes the attempt, which is run again after waiting for the page
+  /// with none of its own locked, so no latch is held across a wait.
+  pub fn apply<F>(&self, mut f: F) -> Result
+  where
+    F: FnMut() -> Result,
+  {
+    while let Some(index) = self.attempt(&mut f)? {
+      self.buffer.wait_unlocked(self.tx_id, index)?;
+    }
+    Ok(())
+  }
+
+  /// Like apply, but gives up instead of waiting and returns false.
+  pub fn try_apply<F>(&self, mut f: F) -> Result<bool>
+  where
+    F: FnMut() -> Result,
+  {
+    self.attempt(&mut f).map(|blocked| blocked.is_none())
   }
 
+  /// Returns the page that was locked by another transaction, if any.
+  /// The pages are logged only at the end, so an undone attempt leaves
+  /// nothing in the log and its pages can be released right away.
+  /// Any other error undoes the attempt as well, so a later commit
+  /// starts from the writes made before it.
+  fn attempt<F>(&self, f: &mut F) -> Result<Option<usize>>
+  where
+    F: FnMut() -> Result,
+  {
+    let written = self.written.l().clone();
+    let locked = self.locked.l().clone();
+    let writes = self.buffer.savepoint(self.tx_id);
+    let records = self.wal.savepoint(self.tx_id);
+    let allocated = self.allocated.l().len();
+    let released = self.released.l().len();
+
+    *self.applying.l() = Some(Default::default());
+    let result = f();
+    let applied = self.applying.l().take().unwrap_or_default();
+    let err = match result.and_then(|_| self.log(applied, released)) {
+      Ok(()) => return Ok(None),
+      Err(err) => err,
+    };
+
+    let restored = self.buffer.rollback_to(self.tx_id, writes)?;
+    let allocated = self.allocated.l().split_off(allocated);
+    let released = self.released.l().split_off(released);
+    if !self.wal.rollback_to(self.tx_id, records)? {
+      // part of the attempt is already in the log, so the restored state
+      // is logged on top of it and its pages stay locked until the end.
+      for (index, page) in restored {
+        if let Some(page) = page {
+          self.wal.append(self.tx_id, index, page)?;
+        }
+      }
+      for index in released {
+        let page = self.buffer.get_latest(self.tx_id, index)?;
+        self.wal.append(self.tx_id, index, page)?;
+      }
+      for index in allocated {
+        self.release(index)?;
+      }
+      return Err(err);
+    }
+
+    for index in allocated {
+      self.freelist.restore(self.tx_id, index);
+    }
+    let mut pages = self.written.l().drain_all();
+    pages.append(&mut self.locked.l());
+    for &index in pages.difference(&written) {
+      if !locked.contains(&index) {
+        self.buffer.unlock(self.tx_id, index);
+      }
+    }
+    *self.written.l() = written;
+    *self.locked.l() = locked;
+    match err {
+      Error::PageLocked(index) => Ok(Some(index)),
+      err => Err(err),
+    }
+  }
+
+  /// Logs the final images of the pages an attempt wrote and the pages it released.
+  fn log(&self, applied: BTreeSet<usize>, released: usize) -> Result {
+    for index in applied {
+      let page = self.buffer.get_latest(self.tx_id, index)?;
+      self.wal.append(self.tx_id, index, page)?;
+    }
+    let released = self.released.l()[released..].to_vec();
+    for index in released {
+      self.wal.release(self.tx_id, index)?;
+    }
+    Ok(())
+  }
+
+  /// Returns the commit index the transaction was given.
+  pub fn commit(&self) -> Result<usize> {
+    let released = self.released.l().drain_all();
+    self.freelist.stage(self.tx_id, released);
+    let commit_index = match self.wal.commit(self.tx_id) {
+      Ok(commit_index) => commit_index,
+      Err(err) => {
+        self.freelist.unstage(self.tx_id);
+        return Err(err);
+      }
+    };
+    self.buffer.finish(self.tx_id, commit_index);
+    self.buffer.unpin_snapshot(self.reader.get_snapshot());
+    self.buffer.unlock_all(self.tx_id);
+    Ok(commit_index)
+  }
+
+  pub fn abort(&self) -> Result {
+    self.pending.l().clear();
+    let result = self
+      .buffer
+      .rollback(self.tx_id)
+      .and_then(|_| self.wal.abort(self.tx_id));
+    if result.is_ok() {
+      self.buffer.finish(self.tx_id, 0);
+    }
+    self.buffer.unpin_snapshot(self.reader.get_snapshot());
+    self.buffer.unlock_all(self.tx_id);
+    self.released.l().clear();
+    self.allocated.l().clear();
+    self.freelist.abort(self.tx_id);
+    result
+  }
+
+  pub fn savepoint(&self) -> Savepoint {
+    Savepoint {
+      tx_id: self.tx_id,
+      pending: self.pending.l().savepoint(),
+      writes: self.buffer.savepoint(self.tx_id),
+      records: self.wal.savepoint(self.tx_id),
+      allocated: self.allocated.l().len(),
+      released: self.released.l().len(),
+    }
+  }
+
+  pub fn rollback_to(&self, savepoint: &Savepoint) -> Result {
+    // a savepoint taken after an earlier rollback target is stale,
+    // which the pending writes tell by its id before anything is undone.
+    if savepoint.tx_id.ne(&self.tx_id) {
+      return Err(Error::Invalid);
+    }
+    self.pending.l().rollback_to(savepoint.pending)?;
+    let allocated = self.allocated.l().split_off(savepoint.allocated);
+    let released = self.released.l().split_off(savepoint.released);
+    let restored = self.buffer.rollback_to(self.tx_id, savepoint.writes)?;
+
+    if self.wal.rollback_to(self.tx_id, savepoint.records)? {
+      for index in allocated {
+        self.freelist.restore(self.tx_id, index);
+      }
+      return Ok(());
+    }
+
+    // part of the undone work is already in the log,
+    // so the restored state is logged on top of it.
+    for (index, page) in restored {
+      if let Some(page) = page {
+        self.wal.append(self.tx_id, index, page)?;
+      }
+    }
+    for index in released {
+      let page = self.buffer.get_latest(self.tx_id, index)?;
+      self.wal.append(self.tx_id, index, page)?;
+    }
+    for index in allocated {
+      self.release(index)?;
+    }
+    Ok(())
+  }
+
+  /// The page stays locked until the end of the transaction,
+  /// as the committed tree still points to it.
   pub fn release(&self, index: usize) -> Result {
-    unimplemented!()
+    if self.applying.l().is_none() {
+      self.wal.release(self.tx_id, index)?;
+    }
+    self.written.l().insert(index);
+    self.released.l().push(index);
+    Ok(())
   }
 }
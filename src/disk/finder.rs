@@ -31,6 +31,7 @@ pub struct Finder<const N: usize> {
   write_c: AtomicUsize,
   flush_th: Arc<BackgroundThread<(), std::io::Result<()>>>,
   meta_th: BackgroundThread<(), std::io::Result<Metadata>>,
+  truncate_th: BackgroundThread<usize, std::io::Result<()>>,
 }
 impl<const N: usize> Finder<N> {
   pub fn open(config: FinderConfig) -> Result<Self> {
@@ -105,6 +106,13 @@ impl<const N: usize> Finder<N> {
       BackgroundWork::no_timeout(move |_| mf.metadata()),
     );
 
+    let tf = file.copy().map_err(Error::IO)?;
+    let truncate_th = BackgroundThread::new(
+      format!("truncate {}", config.path.to_string_lossy()),
+      N,
+      BackgroundWork::no_timeout(move |len: usize| tf.truncate(len.mul(N) as u64)),
+    );
+
     Ok(Self {
       read_ths,
       read_c: AtomicUsize::new(0),
@@ -112,6 +120,7 @@ impl<const N: usize> Finder<N> {
       write_c: AtomicUsize::new(0),
       flush_th,
       meta_th,
+      truncate_th,
     })
   }
 
@@ -149,10 +158,15 @@ impl<const N: usize> Finder<N> {
       th.close();
     }
     self.flush_th.close();
+    self.truncate_th.close();
   }
 
   pub fn len(&self) -> Result<usize> {
     let meta = self.meta_th.send_await(()).map_err(Error::IO)?;
     Ok(meta.len() as usize / N)
   }
+
+  pub fn truncate(&self, len: usize) -> Result {
+    self.truncate_th.send_await(len).map_err(Error::IO)
+  }
 }
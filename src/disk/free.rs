@@ -1,6 +1,7 @@
 use std::{
-  collections::BTreeSet,
-  ops::Mul,
+  collections::BTreeMap,
+  ops::{Add, Div, Mul, Rem, Sub, SubAssign},
+  path::PathBuf,
   sync::{
     atomic::{AtomicUsize, Ordering},
     Arc, Mutex,
@@ -8,58 +9,427 @@ use std::{
   time::Duration,
 };
 
-use crate::{BackgroundThread, BackgroundWork, Result, ShortenedMutex};
+use crate::{
+  logger, BackgroundThread, BackgroundWork, Error, Page, Result, Serializable,
+  ShortenedMutex,
+};
+
+use super::{Finder, FinderConfig};
+
+/// Meta pages live at 0 and 1, and each slot owns every other page after them.
+const META_SLOTS: usize = 2;
 
-use super::Finder;
+pub struct FreeListConfig {
+  pub path: PathBuf,
+  pub interval: Duration,
+  pub batch_delay: Duration,
+  pub batch_size: usize,
+}
 
 pub struct FreeList<const N: usize> {
-  list: Arc<Mutex<BTreeSet<usize>>>,
+  /// free indexes with the commit index they were released at,
+  /// 0 when no snapshot could ever read them.
+  list: Arc<Mutex<BTreeMap<usize, usize>>>,
+  staged: Mutex<BTreeMap<usize, Vec<usize>>>,
+  /// indexes handed to each running transaction, persisted as free
+  /// as nothing adds them back if it never commits.
+  acquired: Mutex<BTreeMap<usize, Vec<usize>>>,
   file: Arc<Finder<N>>,
+  disk: Finder<N>,
   chan: BackgroundThread<(), Result>,
+  interval: Duration,
   last_index: AtomicUsize,
+  version: AtomicUsize,
+  applied: usize,
 }
 impl<const N: usize> FreeList<N> {
-  pub fn new(interval: Duration, file: Arc<Finder<N>>) -> Result<Self> {
-    let chan = BackgroundThread::new(
-      "defragmentation",
-      N.mul(2),
-      BackgroundWork::with_timeout(interval, move |_| {
-        // let len = file.len()?;
-        // for i in 0..len {
-        //   if let Err(Error::NotFound) = file.read(i) {
-        //     list.l().insert(i);
-        //   };
-        // }
-        Ok(())
-      }),
-    );
-    let last_index = file.len()?;
+  pub fn open(config: FreeListConfig, file: Arc<Finder<N>>) -> Result<Self> {
+    let chan = BackgroundThread::empty("defragmentation", N.mul(1000));
+    let disk = Finder::open(FinderConfig {
+      path: config.path,
+      batch_delay: config.batch_delay,
+      batch_size: config.batch_size,
+      read_threads: None,
+      write_threads: None,
+    })?;
+
+    let (meta, list) = match Self::load(&disk)? {
+      Some((meta, list)) => (meta, list),
+      None => (FreeMeta::default(), Default::default()),
+    };
+    let last_index = file.len()?.max(meta.last_index);
     Ok(Self {
-      list: Default::default(),
+      list: Arc::new(Mutex::new(list)),
+      staged: Default::default(),
+      acquired: Default::default(),
       file,
+      disk,
       chan,
+      interval: config.interval,
       last_index: AtomicUsize::new(last_index),
+      version: AtomicUsize::new(meta.version),
+      applied: meta.applied,
     })
   }
 
-  pub fn acquire(&self) -> usize {
-    if let Some(i) = self.list.l().pop_first() {
-      return i;
+  fn load(disk: &Finder<N>) -> Result<Option<(FreeMeta, BTreeMap<usize, usize>)>> {
+    let mut metas = vec![];
+    for slot in 0..META_SLOTS {
+      match disk.read(slot)?.deserialize::<FreeMeta, Error>() {
+        Ok(meta) => metas.push(meta),
+        Err(Error::NotFound) => continue,
+        Err(err) => return Err(err),
+      };
+    }
+    let meta = match metas.into_iter().max_by_key(|m| m.version) {
+      Some(meta) => meta,
+      None => return Ok(None),
+    };
+
+    let slot = meta.version.rem(META_SLOTS);
+    let mut list = BTreeMap::new();
+    for k in 0..meta.pages {
+      let page: FreePage = disk.read(data_index(slot, k))?.deserialize()?;
+      list.extend(page.indexes.into_iter().map(|i| (i, 0)));
+    }
+    Ok(Some((meta, list)))
+  }
+
+  /// WAL index that the last persisted list already reflects.
+  pub fn applied_index(&self) -> usize {
+    self.applied
+  }
+
+  /// `applied` must be read before the list is snapshotted,
+  /// so replaying the transactions committed after it
+  /// only re-adds or re-removes indexes.
+  pub fn persist(&self, applied: usize) -> Result {
+    let list: Vec<usize> = {
+      let list = self.list.l();
+      let mut indexes: Vec<usize> = list.keys().copied().collect();
+      indexes.extend(self.acquired.l().values().flatten());
+      indexes.sort();
+      indexes
+    };
+    let last_index = self.last_index.load(Ordering::SeqCst);
+    let version = self.version.fetch_add(1, Ordering::SeqCst).add(1);
+    let slot = version.rem(META_SLOTS);
+
+    let chunks: Vec<&[usize]> = list.chunks(FreePage::capacity::<N>()).collect();
+    for (k, chunk) in chunks.iter().enumerate() {
+      let page = FreePage {
+        indexes: chunk.to_vec(),
+      };
+      self.disk.write(data_index(slot, k), page.serialize()?)?;
+    }
+    self.disk.fsync()?;
+
+    let meta = FreeMeta {
+      version,
+      applied,
+      last_index,
+      pages: chunks.len(),
+    };
+    self.disk.write(slot, meta.serialize()?)?;
+    self.disk.fsync()
+  }
+
+  pub fn start_defragmentation<F>(&self, mut f: F)
+  where
+    F: FnMut() -> Result + Send + 'static,
+  {
+    self.chan.set_work(BackgroundWork::with_timeout(
+      self.interval,
+      move |v: Option<()>| match v {
+        Some(_) => Ok(()),
+        None => f().inspect_err(|err| {
+          logger::error(format!("defragmentation failed {:?}", err));
+        }),
+      },
+    ));
+    self.chan.send(());
+  }
+
+  pub fn stop_defragmentation(&self) {
+    self.chan.close();
+  }
+
+  pub fn acquire(&self, tx_id: usize) -> usize {
+    let mut list = self.list.l();
+    let i = match list.pop_first() {
+      Some((i, _)) => i,
+      None => self.last_index.fetch_add(1, Ordering::SeqCst),
+    };
+    self.acquired.l().entry(tx_id).or_default().push(i);
+    i
+  }
+
+  pub fn acquire_below(&self, tx_id: usize, limit: usize) -> Option<usize> {
+    let mut list = self.list.l();
+    if list.first_key_value()?.0.ge(&limit) {
+      return None;
     }
+    let (i, _) = list.pop_first()?;
+    self.acquired.l().entry(tx_id).or_default().push(i);
+    Some(i)
+  }
 
-    self.last_index.fetch_add(1, Ordering::SeqCst)
+  /// Whether a free index lies below a page in use, so moving pages helps.
+  pub fn fragmented(&self) -> bool {
+    let list = self.list.l();
+    match list.first_key_value() {
+      Some((first, _)) => first
+        .add(list.len())
+        .lt(&self.last_index.load(Ordering::SeqCst)),
+      None => false,
+    }
+  }
+
+  /// Drops the free indexes from the tail released at or before the horizon,
+  /// returns them with the latest commit index they were released at.
+  pub fn shrink(&self, horizon: usize) -> (Vec<usize>, usize) {
+    let mut list = self.list.l();
+    let mut last_index = self.last_index.load(Ordering::SeqCst);
+    let mut removed = vec![];
+    let mut released = 0;
+    while last_index.gt(&0) {
+      match list.get(&last_index.sub(1)) {
+        Some(&commit_index) if commit_index.le(&horizon) => {
+          released = released.max(commit_index)
+        }
+        _ => break,
+      };
+      last_index.sub_assign(1);
+      list.remove(&last_index);
+      removed.push(last_index);
+    }
+    if !removed.is_empty() {
+      self.last_index.store(last_index, Ordering::SeqCst);
+    }
+    (removed, released)
+  }
+
+  /// Should only be called once the shrunk indexes are out of the cache,
+  /// or a flush can extend the file again.
+  pub fn truncate(&self) -> Result {
+    self.file.truncate(self.last_index.load(Ordering::SeqCst))
   }
 
   pub fn fetch(&self, i: usize) {
     self.last_index.store(i, Ordering::SeqCst)
   }
 
+  /// The index was never visible to a committed snapshot.
   pub fn insert(&self, i: usize) {
-    self.list.l().insert(i);
+    self.list.l().insert(i, 0);
+  }
+
+  /// Gives back an index the transaction acquired and no longer uses.
+  pub fn restore(&self, tx_id: usize, i: usize) {
+    let mut list = self.list.l();
+    if let Some(indexes) = self.acquired.l().get_mut(&tx_id) {
+      indexes.retain(|&index| index.ne(&i));
+    }
+    list.insert(i, 0);
+  }
+
+  /// Gives back every index the transaction acquired, none of them is used.
+  pub fn abort(&self, tx_id: usize) {
+    let mut list = self.list.l();
+    for i in self.acquired.l().remove(&tx_id).unwrap_or_default() {
+      list.insert(i, 0);
+    }
+  }
+
+  /// Holds the indexes released by the transaction until its commit is applied.
+  pub fn stage(&self, tx_id: usize, indexes: Vec<usize>) {
+    self.staged.l().insert(tx_id, indexes);
+  }
+
+  pub fn unstage(&self, tx_id: usize) {
+    self.staged.l().remove(&tx_id);
+  }
+
+  /// Called before the commit index is published,
+  /// so a list persisted at that index already has the releases.
+  /// The acquired indexes are in use from then on.
+  pub fn commit(&self, tx_id: usize, commit_index: usize) {
+    let mut list = self.list.l();
+    self.acquired.l().remove(&tx_id);
+    if let Some(indexes) = self.staged.l().remove(&tx_id) {
+      list.extend(indexes.into_iter().map(|i| (i, commit_index)));
+    }
+  }
+
+  pub fn remove(&self, i: usize) {
+    let mut list = self.list.l();
+    list.remove(&i);
+    self.last_index.fetch_max(i.add(1), Ordering::SeqCst);
+  }
+
+  #[cfg(test)]
+  pub fn len(&self) -> usize {
+    self.list.l().len()
   }
 
   pub fn before_shutdown(&self) {
     self.chan.close();
     self.file.close();
+    self.disk.close();
+  }
+}
+
+fn data_index(slot: usize, k: usize) -> usize {
+  k.mul(META_SLOTS).add(META_SLOTS).add(slot)
+}
+
+#[derive(Debug, Default)]
+struct FreeMeta {
+  version: usize,
+  applied: usize,
+  last_index: usize,
+  pages: usize,
+}
+impl<const N: usize> Serializable<Error, N> for FreeMeta {
+  fn serialize(&self) -> Result<Page<N>> {
+    let mut page = Page::new();
+    let mut wt = page.writer();
+    wt.write(&self.version.to_be_bytes())?;
+    wt.write(&self.applied.to_be_bytes())?;
+    wt.write(&self.last_index.to_be_bytes())?;
+    wt.write(&self.pages.to_be_bytes())?;
+    Ok(page)
+  }
+
+  fn deserialize(value: &Page<N>) -> Result<Self> {
+    if value.is_empty() {
+      return Err(Error::NotFound);
+    }
+
+    let mut sc = value.scanner();
+    Ok(Self {
+      version: sc.read_usize()?,
+      applied: sc.read_usize()?,
+      last_index: sc.read_usize()?,
+      pages: sc.read_usize()?,
+    })
+  }
+}
+
+#[derive(Debug)]
+struct FreePage {
+  indexes: Vec<usize>,
+}
+impl FreePage {
+  fn capacity<const N: usize>() -> usize {
+    N.sub(2).sub(8).div(8)
+  }
+}
+impl<const N: usize> Serializable<Error, N> for FreePage {
+  fn serialize(&self) -> Result<Page<N>> {
+    let mut page = Page::new();
+    let mut wt = page.writer();
+    wt.write(&self.indexes.len().to_be_bytes())?;
+    for i in &self.indexes {
+      wt.write(&i.to_be_bytes())?;
+    }
+    Ok(page)
+  }
+
+  fn deserialize(value: &Page<N>) -> Result<Self> {
+    let mut sc = value.scanner();
+    let len = sc.read_usize()?;
+    let mut indexes = Vec::with_capacity(len);
+    for _ in 0..len {
+      indexes.push(sc.read_usize()?);
+    }
+    Ok(Self { indexes })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{fs, ops::Add, path::Path, sync::Arc, time::Duration};
+
+  use crate::{
+    disk::{Finder, FinderConfig},
+    Page, Serializable,
+  };
+
+  use super::{FreeList, FreeListConfig, FreeMeta, FreePage};
+
+  const N: usize = 4096;
+
+  fn open(path: &Path) -> FreeList<N> {
+    let file = Finder::open(FinderConfig {
+      path: path.join("data.db"),
+      batch_delay: Duration::from_millis(1),
+      batch_size: 10,
+      read_threads: None,
+      write_threads: None,
+    })
+    .unwrap();
+    let config = FreeListConfig {
+      path: path.join("free.db"),
+      interval: Duration::from_secs(1),
+      batch_delay: Duration::from_millis(1),
+      batch_size: 10,
+    };
+    FreeList::open(config, Arc::new(file)).unwrap()
+  }
+
+  #[test]
+  fn _1() {
+    let page = FreePage {
+      indexes: (0..FreePage::capacity::<N>()).collect(),
+    };
+    let p: Page<N> = page.serialize().unwrap();
+    let decoded: FreePage = p.deserialize().unwrap();
+    assert_eq!(decoded.indexes, page.indexes);
+
+    let overflow = FreePage {
+      indexes: (0..FreePage::capacity::<N>().add(1)).collect(),
+    };
+    assert!(Serializable::<_, N>::serialize(&overflow).is_err());
+
+    let meta = FreeMeta {
+      version: 3,
+      applied: 10,
+      last_index: 42,
+      pages: 2,
+    };
+    let p: Page<N> = meta.serialize().unwrap();
+    let decoded: FreeMeta = p.deserialize().unwrap();
+    assert_eq!(decoded.version, 3);
+    assert_eq!(decoded.applied, 10);
+    assert_eq!(decoded.last_index, 42);
+    assert_eq!(decoded.pages, 2);
+    assert!(Page::<N>::new_empty().deserialize::<FreeMeta, _>().is_err());
+  }
+
+  /// An index taken by a transaction that never commits is free after a restart.
+  #[test]
+  fn _2() {
+    let path = std::env::temp_dir().join(format!("lfkv-free-2-{}", std::process::id()));
+    fs::remove_dir_all(&path).ok();
+    fs::create_dir_all(&path).unwrap();
+
+    let freelist = open(&path);
+    let running = freelist.acquire(1);
+    let used = freelist.acquire(2);
+    let aborted = freelist.acquire(3);
+    freelist.abort(3);
+    freelist.commit(2, 5);
+    freelist.persist(5).unwrap();
+    freelist.before_shutdown();
+
+    let freelist = open(&path);
+    assert_eq!(freelist.len(), 2);
+    let mut acquired = vec![freelist.acquire(4), freelist.acquire(4)];
+    acquired.sort();
+    assert_eq!(acquired, vec![running, aborted]);
+    assert!(freelist.acquire(4).gt(&used));
+    freelist.before_shutdown();
+    fs::remove_dir_all(&path).ok();
   }
 }
@@ -7,7 +7,7 @@ use std::mem;
 use std::os::fd::{FromRawFd, RawFd};
 #[cfg(any(target_os = "macos", target_os = "linux"))]
 use std::os::unix::fs::FileExt;
-#[cfg(target_os = "macos")]
+#[cfg(any(target_os = "macos", target_os = "linux"))]
 use std::os::unix::io::AsRawFd;
 #[cfg(target_os = "windows")]
 #[cfg(target_os = "windows")]
@@ -42,6 +42,7 @@ pub trait DirectIO {
   fn direct_io<P: AsRef<Path>>(&self, path: P) -> Result<CopyableFile>;
 }
 
+#[cfg(target_os = "macos")]
 impl DirectIO for OpenOptions {
   fn direct_io<P: AsRef<Path>>(&self, path: P) -> Result<CopyableFile> {
     let file = self.open(path)?;
@@ -54,11 +55,8 @@ impl DirectIO for OpenOptions {
 }
 #[cfg(target_os = "linux")]
 impl DirectIO for OpenOptions {
-  fn direct_io<P: AsRef<Path>>(&self, path: P) -> Result<File> {
-    self
-      .custom_flags(libc::O_DIRECT)
-      .open(path)
-      .map(CopyableFile)
+  fn direct_io<P: AsRef<Path>>(&self, path: P) -> Result<CopyableFile> {
+    self.open(path).map(CopyableFile)
   }
 }
 #[cfg(target_os = "windows")]
@@ -126,6 +124,10 @@ impl CopyableFile {
     self.0.metadata()
   }
 
+  pub fn truncate(&self, len: u64) -> Result<()> {
+    self.0.set_len(len)
+  }
+
   #[allow(unused)]
   pub fn append(&self, buf: &[u8]) -> Result<usize> {
     let _ = FLock::new(&self.0)?;
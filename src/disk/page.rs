@@ -6,7 +6,9 @@ use crate::error::{Error, Result};
 
 use super::Serializable;
 
-pub const PAGE_SIZE: usize = size::kb(4) - 24;
+/// A block keeps 25 bytes of header in front of the page,
+/// so the page takes what is left of 4KB after 32 reserved bytes.
+pub const PAGE_SIZE: usize = size::kb(4) - 32;
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Page<const T: usize = PAGE_SIZE> {
@@ -128,6 +130,12 @@ impl<'a, const T: usize> PageScanner<'a, T> {
     Ok(b)
   }
 
+  pub fn read_u16(&mut self) -> Result<u16> {
+    let mut b = [0; 2];
+    b.copy_from_slice(self.read_n(2)?);
+    Ok(u16::from_be_bytes(b))
+  }
+
   pub fn read_usize(&mut self) -> Result<usize> {
     let mut b = [0; 8];
     b.copy_from_slice(self.read_n(8)?);
@@ -6,17 +6,18 @@ use std::{
     atomic::{AtomicBool, Ordering},
     Arc,
   },
-  time::Duration,
+  time::{Duration, SystemTime},
 };
 
 use sysinfo::System;
 
 use crate::{
   buffer::{BufferPool, RollbackStorage, RollbackStorageConfig, BLOCK_SIZE},
-  disk::{Finder, FinderConfig, FreeList},
+  disk::{Finder, FinderConfig, FreeList, FreeListConfig},
   logger,
   wal::{WriteAheadLog, WriteAheadLogConfig},
-  Cursor, Error, Result,
+  Cursor, Error, KeyLocks, ReadCursor, Result, SerializableTracker, TransactionOptions,
+  MAX_KEY_SIZE,
 };
 
 pub struct EngineConfig<T>
@@ -35,23 +36,33 @@ where
   pub checkpoint_count: usize,
   pub group_commit_delay: Duration,
   pub group_commit_count: usize,
+  pub max_key_size: usize,
+  pub lock_timeout: Duration,
 }
 
 const WAL_PATH: &str = "wal.db";
 const UNDO_PATH: &str = "undo.db";
 const DISK_PATH: &str = "data.db";
+const FREE_PATH: &str = "free.db";
 
 pub struct Engine {
   wal: Arc<WriteAheadLog>,
   buffer_pool: Arc<BufferPool>,
   freelist: Arc<FreeList<BLOCK_SIZE>>,
+  tracker: Arc<SerializableTracker>,
+  locks: Arc<KeyLocks>,
   available: AtomicBool,
+  max_key_size: usize,
 }
 impl Engine {
   pub fn bootstrap<T>(config: EngineConfig<T>) -> Result<Self>
   where
     T: AsRef<Path>,
   {
+    if config.max_key_size.gt(&MAX_KEY_SIZE) {
+      return Err(Error::Invalid);
+    }
+
     let mem_size = System::new_all().total_memory() as usize;
     logger::info(format!("{} system memory", mem_size));
     fs::create_dir_all(config.base_path.as_ref()).map_err(Error::IO)?;
@@ -65,8 +76,13 @@ impl Engine {
     })?);
     logger::info(format!("disk created"));
 
-    let freelist = Arc::new(FreeList::new(
-      config.defragmentation_interval,
+    let freelist = Arc::new(FreeList::open(
+      FreeListConfig {
+        path: config.base_path.as_ref().join(FREE_PATH),
+        interval: config.defragmentation_interval,
+        batch_delay: config.disk_batch_delay,
+        batch_size: config.disk_batch_size,
+      },
       disk.clone(),
     )?);
     logger::info(format!("freelist created"));
@@ -80,8 +96,12 @@ impl Engine {
     })?);
     logger::info(format!("undo log created"));
 
-    let (bp, flush_c, commit_c) =
-      BufferPool::generate(rollback, disk, mem_size.div_ceil(10).mul(3));
+    let (bp, flush_c, commit_c) = BufferPool::generate(
+      rollback,
+      disk,
+      mem_size.div_ceil(10).mul(3),
+      config.lock_timeout,
+    );
     let buffer_pool = Arc::new(bp);
     logger::info(format!("buffer pool created"));
 
@@ -98,25 +118,81 @@ impl Engine {
       Arc::new(commit_c),
       flush_c,
       &buffer_pool,
+      &freelist,
     )?);
     logger::info("wal created");
+    buffer_pool.start_history(wal.last_commit_index());
 
     let engine = Self {
       wal,
       buffer_pool,
       freelist,
+      tracker: Default::default(),
+      locks: Arc::new(KeyLocks::new(config.lock_timeout)),
       available: AtomicBool::new(true),
+      max_key_size: config.max_key_size,
     };
 
     let cursor = engine.new_transaction()?;
     cursor.initialize()?;
     cursor.commit()?;
 
+    engine
+      .freelist
+      .start_defragmentation(engine.defragmentation());
+
     logger::info("engine initialized");
     Ok(engine)
   }
 
+  /// Moves pages into the free slots below them and truncates the free tail.
+  /// The job is owned by the free list, so it only holds weak references.
+  fn defragmentation(&self) -> impl FnMut() -> Result + Send + 'static {
+    let freelist = Arc::downgrade(&self.freelist);
+    let wal = Arc::downgrade(&self.wal);
+    let buffer_pool = Arc::downgrade(&self.buffer_pool);
+    let tracker = self.tracker.clone();
+    let locks = self.locks.clone();
+    let max_key_size = self.max_key_size;
+    move || {
+      let (freelist, wal, buffer_pool) =
+        match (freelist.upgrade(), wal.upgrade(), buffer_pool.upgrade()) {
+          (Some(freelist), Some(wal), Some(buffer_pool)) => (freelist, wal, buffer_pool),
+          _ => return Ok(()),
+        };
+
+      if freelist.fragmented() {
+        let cursor = Cursor::new(
+          freelist.clone(),
+          wal,
+          buffer_pool.clone(),
+          tracker.clone(),
+          locks.clone(),
+          max_key_size,
+          Default::default(),
+        )?;
+        let moved = cursor.defragment()?;
+        cursor.commit()?;
+        logger::info(format!("defragmentation moved {} pages", moved));
+      }
+
+      // pages are only cut off once no pinned snapshot can reach them.
+      let removed = buffer_pool.retain_with(|oldest| freelist.shrink(oldest));
+      if removed.is_empty() {
+        return Ok(());
+      }
+      for &index in &removed {
+        buffer_pool.discard(index);
+      }
+      freelist.truncate()
+    }
+  }
+
   pub fn new_transaction(&self) -> Result<Cursor> {
+    self.new_transaction_with(Default::default())
+  }
+
+  pub fn new_transaction_with(&self, options: TransactionOptions) -> Result<Cursor> {
     if !self.available.load(Ordering::SeqCst) {
       return Err(Error::EngineUnavailable);
     }
@@ -125,15 +201,259 @@ impl Engine {
       self.freelist.clone(),
       self.wal.clone(),
       self.buffer_pool.clone(),
+      self.tracker.clone(),
+      self.locks.clone(),
+      self.max_key_size,
+      options,
     )
   }
+
+  /// Reads the latest committed state without starting a transaction.
+  pub fn read_transaction(&self) -> Result<ReadCursor> {
+    if !self.available.load(Ordering::SeqCst) {
+      return Err(Error::EngineUnavailable);
+    }
+
+    let snapshot = self
+      .buffer_pool
+      .pin_snapshot(|| self.wal.last_commit_index());
+    Ok(ReadCursor::new(self.buffer_pool.clone(), snapshot))
+  }
+
+  /// Reads the state as of the commit index,
+  /// which fails once the versions it needs may have been reclaimed.
+  pub fn read_at(&self, commit_index: usize) -> Result<ReadCursor> {
+    if !self.available.load(Ordering::SeqCst) {
+      return Err(Error::EngineUnavailable);
+    }
+    if commit_index.gt(&self.wal.last_commit_index()) {
+      return Err(Error::Invalid);
+    }
+
+    let snapshot = self.buffer_pool.pin_snapshot_at(commit_index)?;
+    Ok(ReadCursor::new(self.buffer_pool.clone(), snapshot))
+  }
+
+  /// Reads the state as of the latest commit visible at the time.
+  pub fn read_at_time(&self, time: SystemTime) -> Result<ReadCursor> {
+    let commit_index = self
+      .buffer_pool
+      .commit_index_at(time)
+      .ok_or(Error::SnapshotTooOld)?;
+    self.read_at(commit_index)
+  }
+
+  pub fn create_keyspace(&self, name: &str) -> Result {
+    let cursor = self.new_transaction()?;
+    cursor.create_keyspace(name)?;
+    cursor.commit()
+  }
 }
 
 impl Drop for Engine {
   fn drop(&mut self) {
     self.available.store(false, Ordering::SeqCst);
+    self.freelist.stop_defragmentation();
     self.wal.before_shutdown();
     self.buffer_pool.before_shutdown();
     self.freelist.before_shutdown();
   }
 }
+
+/// An engine in its own temporary directory, removed once the test is done.
+#[cfg(test)]
+pub(crate) struct TestEngine {
+  engine: Option<Engine>,
+  path: std::path::PathBuf,
+}
+#[cfg(test)]
+impl TestEngine {
+  pub fn open(name: &str) -> Self {
+    let path = std::env::temp_dir().join(format!("lfkv-{}-{}", name, std::process::id()));
+    fs::remove_dir_all(&path).ok();
+    let engine = Engine::bootstrap(Self::config(&path)).unwrap();
+    Self {
+      engine: Some(engine),
+      path,
+    }
+  }
+
+  /// Leaves the engine running without a shutdown checkpoint
+  /// and opens the files again, so they are recovered from the log.
+  pub fn crash(mut self) -> Self {
+    std::mem::forget(self.engine.take());
+    self.engine = Some(Engine::bootstrap(Self::config(&self.path)).unwrap());
+    self
+  }
+
+  pub fn freelist(&self) -> &FreeList<BLOCK_SIZE> {
+    &self.freelist
+  }
+
+  pub fn oldest_snapshot(&self) -> usize {
+    self.buffer_pool.oldest_snapshot()
+  }
+
+  /// Runs one pass of the defragmentation job.
+  pub fn defragment(&self) {
+    (self.defragmentation())().unwrap();
+  }
+
+  pub fn disk_len(&self) -> u64 {
+    fs::metadata(self.path.join(DISK_PATH)).unwrap().len()
+  }
+
+  /// Writes out the buffered log, flushes the buffer pool
+  /// and logs a checkpoint before returning.
+  pub fn checkpoint(&self) {
+    self.wal.checkpoint().unwrap();
+  }
+
+  fn config(path: &Path) -> EngineConfig<std::path::PathBuf> {
+    EngineConfig {
+      base_path: path.to_path_buf(),
+      disk_batch_delay: Duration::from_millis(1),
+      disk_batch_size: 100,
+      defragmentation_interval: Duration::from_secs(60 * 60),
+      undo_batch_delay: Duration::from_millis(1),
+      undo_batch_size: 100,
+      undo_file_size: crate::size::mb(1),
+      wal_file_size: crate::size::mb(4),
+      checkpoint_interval: Duration::from_secs(60 * 60),
+      checkpoint_count: 100000,
+      group_commit_delay: Duration::from_millis(1),
+      group_commit_count: 100,
+      max_key_size: MAX_KEY_SIZE,
+      lock_timeout: Duration::from_millis(500),
+    }
+  }
+}
+#[cfg(test)]
+impl std::ops::Deref for TestEngine {
+  type Target = Engine;
+
+  fn deref(&self) -> &Engine {
+    self.engine.as_ref().unwrap()
+  }
+}
+#[cfg(test)]
+impl Drop for TestEngine {
+  fn drop(&mut self) {
+    drop(self.engine.take());
+    fs::remove_dir_all(&self.path).ok();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn _1() {
+    let path = std::env::temp_dir().join(format!("lfkv-engine-1-{}", std::process::id()));
+    let mut config = TestEngine::config(&path);
+    config.max_key_size = MAX_KEY_SIZE + 1;
+    assert!(matches!(Engine::bootstrap(config), Err(Error::Invalid)));
+    fs::remove_dir_all(&path).ok();
+  }
+
+  #[test]
+  fn _2() {
+    let engine = TestEngine::open("engine-2");
+    let key = |i: usize| format!("key{:02}", i).into_bytes();
+    let cursor = engine.new_transaction().unwrap();
+    for i in 0..60 {
+      cursor.insert(key(i), vec![i as u8; 1000]).unwrap();
+    }
+    cursor.commit().unwrap();
+
+    let old = engine.read_transaction().unwrap();
+    let cursor = engine.new_transaction().unwrap();
+    for i in 0..50 {
+      assert!(cursor.delete(&key(i)).unwrap());
+    }
+    cursor.commit().unwrap();
+    engine.checkpoint();
+    let len = engine.disk_len();
+
+    engine.defragment();
+    engine.checkpoint();
+    assert!(engine.disk_len().ge(&len));
+    for i in 0..60 {
+      assert_eq!(old.get(&key(i)).unwrap(), vec![i as u8; 1000]);
+    }
+    let snapshot = old.get_snapshot();
+    let at = engine.read_at(snapshot).unwrap();
+    assert_eq!(at.get(&key(0)).unwrap(), vec![0; 1000]);
+    drop((old, at));
+
+    engine.defragment();
+    engine.checkpoint();
+    assert!(engine.disk_len().lt(&len));
+    assert!(matches!(
+      engine.read_at(snapshot),
+      Err(Error::SnapshotTooOld)
+    ));
+    let cursor = engine.new_transaction().unwrap();
+    assert_eq!(cursor.range(..).unwrap().count(), 10);
+    for i in 50..60 {
+      assert_eq!(cursor.get(&key(i)).unwrap(), vec![i as u8; 1000]);
+    }
+    cursor.commit().unwrap();
+  }
+
+  #[test]
+  fn _3() {
+    let engine = TestEngine::open("engine-3");
+    let freelist = Arc::downgrade(&engine.freelist);
+    let wal = Arc::downgrade(&engine.wal);
+    drop(engine);
+    assert!(freelist.upgrade().is_none());
+    assert!(wal.upgrade().is_none());
+  }
+
+  #[test]
+  fn _4() {
+    let engine = TestEngine::open("engine-4");
+    let before = SystemTime::now();
+    let commit = |value: u8| {
+      let cursor = engine.new_transaction().unwrap();
+      cursor.insert(b"key".to_vec(), vec![value; 100]).unwrap();
+      cursor.commit().unwrap();
+      engine.read_transaction().unwrap().get_snapshot()
+    };
+
+    let first = commit(1);
+    let between = SystemTime::now();
+    let second = commit(2);
+
+    let at = engine.read_at(first).unwrap();
+    assert_eq!(at.get(&b"key".to_vec()).unwrap(), vec![1; 100]);
+    drop(at);
+    let at = engine.read_at_time(between).unwrap();
+    assert_eq!(at.get_snapshot(), first);
+    assert_eq!(at.get(&b"key".to_vec()).unwrap(), vec![1; 100]);
+    drop(at);
+    let at = engine.read_at(second).unwrap();
+    assert_eq!(at.get(&b"key".to_vec()).unwrap(), vec![2; 100]);
+    assert!(matches!(engine.read_at(second + 1000), Err(Error::Invalid)));
+    assert!(matches!(
+      engine.read_at_time(before - Duration::from_secs(1)),
+      Err(Error::SnapshotTooOld)
+    ));
+
+    // the undo log only holds a few hundred records with the test config,
+    // but grows rather than drop what the pinned snapshot reads.
+    for i in 0..400 {
+      commit(i as u8);
+    }
+    assert_eq!(at.get(&b"key".to_vec()).unwrap(), vec![2; 100]);
+    drop(at);
+    commit(0);
+    assert!(matches!(engine.read_at(first), Err(Error::SnapshotTooOld)));
+    assert!(matches!(
+      engine.read_at_time(between),
+      Err(Error::SnapshotTooOld)
+    ));
+  }
+}
@@ -25,6 +25,41 @@ pub enum Error {
 
   #[error("memory pool empty")]
   MemoryPoolEmpty,
+
+  #[error("key too large")]
+  KeyTooLarge,
+
+  #[error("keyspace not found")]
+  KeyspaceNotFound,
+
+  #[error("keyspace already exists")]
+  KeyspaceAlreadyExists,
+
+  #[error("invalid keyspace name")]
+  InvalidKeyspaceName,
+
+  #[error("too many keyspaces")]
+  TooManyKeyspaces,
+
+  #[error("write conflict")]
+  Conflict,
+
+  #[error("serialization failure")]
+  SerializationFailure,
+
+  #[error("deadlock detected")]
+  Deadlock,
+
+  #[error("lock wait timeout")]
+  LockTimeout,
+
+  #[error("snapshot too old")]
+  SnapshotTooOld,
+
+  /// Met while a commit applies its writes without waiting,
+  /// which undoes the attempt and retries once the page is free.
+  #[error("page {0} is locked")]
+  PageLocked(usize),
 }
 impl Error {
   pub fn unknown<E>(e: E) -> Error
@@ -33,6 +68,19 @@ impl Error {
   {
     Error::Unknown(e.into())
   }
+
+  /// The transaction failed against a concurrent one, or read past
+  /// the retained versions, and can be run again.
+  pub fn is_retryable(&self) -> bool {
+    matches!(
+      self,
+      Error::Conflict
+        | Error::SerializationFailure
+        | Error::Deadlock
+        | Error::LockTimeout
+        | Error::SnapshotTooOld
+    )
+  }
 }
 
 pub type Result<T = ()> = std::result::Result<T, Error>;
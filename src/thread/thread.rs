@@ -139,7 +139,7 @@ where
     let (tx, rx) = unbounded::<(T, Sender<R>)>();
     let t = std::thread::Builder::new()
       .name(inner.name.clone())
-      .stack_size(inner.size)
+      .stack_size(inner.size.max(1 << 21))
       .spawn(move || {
         func.l().run(rx);
       })
@@ -158,9 +158,12 @@ where
     self.checked_send(v).must_recv()
   }
 
+  /// Joins the thread and drops the work,
+  /// so whatever it captured can be dropped with it.
   pub fn close(&self) {
     let mut inner = self.0.l();
     inner.thread.take().map(|(t, tx)| close_thread(t, tx));
+    inner.func = Arc::new(Mutex::new(BackgroundWork::Empty));
     logger::info(format!("{} thread done", inner.name))
   }
 }
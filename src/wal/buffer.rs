@@ -1,16 +1,17 @@
 use std::{
   collections::BTreeMap,
-  ops::{Add, AddAssign, SubAssign},
+  ops::{Add, AddAssign, Sub, SubAssign},
   sync::Mutex,
 };
 
-use crate::{DrainAll, Page, ShortenedMutex};
+use crate::{DrainAll, Error, Page, Result, ShortenedMutex};
 
 use super::LogRecord;
 
 struct LogBufferCore {
   last_transaction: usize,
   map: BTreeMap<usize, Vec<LogRecord>>,
+  written: BTreeMap<usize, usize>,
   size: usize,
 }
 pub struct LogBuffer(Mutex<LogBufferCore>);
@@ -20,6 +21,7 @@ impl LogBuffer {
     Self(Mutex::new(LogBufferCore {
       last_transaction: 0,
       map: Default::default(),
+      written: Default::default(),
       size: 0,
     }))
   }
@@ -35,6 +37,7 @@ impl LogBuffer {
     let record = LogRecord::new_start(tx_id);
     core.size.add_assign(record.size());
     core.map.insert(tx_id, vec![LogRecord::new_start(tx_id)]);
+    core.written.insert(tx_id, 1);
     core.last_transaction = tx_id;
     tx_id
   }
@@ -44,11 +47,21 @@ impl LogBuffer {
     let record = LogRecord::new_insert(tx_id, page_index, data);
     core.size.add_assign(record.size());
     core.map.entry(tx_id).or_default().push(record);
+    core.written.entry(tx_id).or_default().add_assign(1);
+  }
+
+  pub fn release(&self, tx_id: usize, page_index: usize) {
+    let mut core = self.0.l();
+    let record = LogRecord::new_release(tx_id, page_index);
+    core.size.add_assign(record.size());
+    core.map.entry(tx_id).or_default().push(record);
+    core.written.entry(tx_id).or_default().add_assign(1);
   }
 
   pub fn commit(&self, tx_id: usize) -> Vec<LogRecord> {
     let mut core = self.0.l();
     let mut records = core.map.remove(&tx_id).unwrap_or_default();
+    core.written.remove(&tx_id);
     core
       .size
       .sub_assign(records.iter().fold(0, |a, r| a.add(r.size())));
@@ -58,6 +71,7 @@ impl LogBuffer {
 
   pub fn rollback(&self, tx_id: usize) {
     let mut core = self.0.l();
+    core.written.remove(&tx_id);
     core.map.remove(&tx_id).map(|records| {
       core
         .size
@@ -65,6 +79,28 @@ impl LogBuffer {
     });
   }
 
+  pub fn savepoint(&self, tx_id: usize) -> usize {
+    self.0.l().written.get(&tx_id).copied().unwrap_or(0)
+  }
+
+  /// Drops the records appended after the savepoint,
+  /// returns false if some of them were already flushed.
+  pub fn rollback_to(&self, tx_id: usize, savepoint: usize) -> Result<bool> {
+    let mut core = self.0.l();
+    let written = core.written.get(&tx_id).copied().unwrap_or(0);
+    if savepoint.gt(&written) {
+      return Err(Error::Invalid);
+    }
+    let records = core.map.entry(tx_id).or_default();
+    let buffered = written.sub(records.len());
+    let dropped = records.split_off(savepoint.max(buffered).sub(buffered));
+    core
+      .size
+      .sub_assign(dropped.iter().fold(0, |a, r| a.add(r.size())));
+    core.written.insert(tx_id, written.sub(dropped.len()));
+    Ok(savepoint.ge(&buffered))
+  }
+
   pub fn len(&self) -> usize {
     self.0.l().size
   }
@@ -75,3 +111,43 @@ impl LogBuffer {
     core.map.drain_all().into_values().flatten().collect()
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use crate::{Error, Page};
+
+  use super::LogBuffer;
+
+  #[test]
+  fn _1() {
+    let buffer = LogBuffer::new();
+    let tx_id = buffer.new_transaction();
+    buffer.append(tx_id, 1, Page::new_empty());
+    let savepoint = buffer.savepoint(tx_id);
+    buffer.append(tx_id, 2, Page::new_empty());
+    buffer.release(tx_id, 3);
+    assert!(buffer.rollback_to(tx_id, savepoint).unwrap());
+    assert_eq!(buffer.savepoint(tx_id), savepoint);
+    assert_eq!(buffer.commit(tx_id).len(), 3);
+
+    let tx_id = buffer.new_transaction();
+    let first = buffer.savepoint(tx_id);
+    buffer.append(tx_id, 1, Page::new_empty());
+    let second = buffer.savepoint(tx_id);
+    buffer.append(tx_id, 2, Page::new_empty());
+    assert!(buffer.rollback_to(tx_id, first).unwrap());
+    assert!(matches!(
+      buffer.rollback_to(tx_id, second),
+      Err(Error::Invalid)
+    ));
+    assert_eq!(buffer.commit(tx_id).len(), 2);
+
+    let tx_id = buffer.new_transaction();
+    let savepoint = buffer.savepoint(tx_id);
+    buffer.append(tx_id, 1, Page::new_empty());
+    assert_eq!(buffer.flush().len(), 2);
+    buffer.append(tx_id, 2, Page::new_empty());
+    assert!(!buffer.rollback_to(tx_id, savepoint).unwrap());
+    assert_eq!(buffer.len(), 0);
+  }
+}
@@ -33,6 +33,7 @@ pub enum Operation {
   Abort,
   Checkpoint(usize),
   Insert(InsertLog),
+  Release(usize),
 }
 impl Operation {
   fn size(&self) -> usize {
@@ -42,6 +43,7 @@ impl Operation {
       Operation::Abort => 1,
       Operation::Checkpoint(_) => 9,
       Operation::Insert(_) => 8 + PAGE_SIZE,
+      Operation::Release(_) => 9,
     }
   }
 }
@@ -62,7 +64,7 @@ impl LogRecord {
   }
 
   pub fn new_abort(transaction_id: usize) -> Self {
-    Self::new(0, transaction_id, Operation::Commit)
+    Self::new(0, transaction_id, Operation::Abort)
   }
 
   pub fn new_insert(transaction_id: usize, page_index: usize, data: Page) -> Self {
@@ -73,6 +75,10 @@ impl LogRecord {
     )
   }
 
+  pub fn new_release(transaction_id: usize, page_index: usize) -> Self {
+    Self::new(0, transaction_id, Operation::Release(page_index))
+  }
+
   pub fn new_checkpoint(applied: usize) -> Self {
     Self::new(0, 0, Operation::Checkpoint(applied))
   }
@@ -115,6 +121,10 @@ impl LogRecord {
         wt.write(log.page_index.to_be_bytes().as_ref())?;
         wt.write(log.data.as_ref())?;
       }
+      Operation::Release(i) => {
+        wt.write(&[5])?;
+        wt.write(&i.to_be_bytes())?;
+      }
     }
     Ok(())
   }
@@ -135,6 +145,10 @@ impl LogRecord {
         let data = sc.read_n(PAGE_SIZE)?.into();
         Operation::Insert(InsertLog::new(page_index, data))
       }
+      5 => {
+        let i = sc.read_usize()?;
+        Operation::Release(i)
+      }
       _ => return Err(Error::Invalid),
     };
     return Ok(Self::new(index, transaction_id, operation));
@@ -1,14 +1,14 @@
 use std::{
   collections::{BTreeMap, BTreeSet},
-  ops::{Add, AddAssign, DivAssign, Mul},
+  ops::{Add, AddAssign, DivAssign, Mul, Sub},
   path::PathBuf,
   sync::{Arc, RwLock},
   time::Duration,
 };
 
 use crate::{
-  buffer::BufferPool,
-  disk::{Finder, FinderConfig},
+  buffer::{BufferPool, BLOCK_SIZE},
+  disk::{Finder, FinderConfig, FreeList},
   logger, size, BackgroundThread, BackgroundWork, DrainAll, Page, Result, Serializable,
   ShortenedRwLock,
 };
@@ -30,7 +30,7 @@ pub struct WriteAheadLog {
   buffer: Arc<LogBuffer>,
   commit_c: Arc<BackgroundThread<CommitInfo, Result>>,
   disk: Arc<Finder<WAL_PAGE_SIZE>>,
-  io_c: Arc<BackgroundThread<Vec<LogRecord>, Result>>,
+  io_c: Arc<BackgroundThread<Vec<LogRecord>, Result<usize>>>,
   checkpoint_c: Arc<BackgroundThread<()>>,
   config: WriteAheadLogConfig,
   last_index: Arc<RwLock<usize>>,
@@ -41,6 +41,7 @@ impl WriteAheadLog {
     commit_c: Arc<BackgroundThread<CommitInfo, Result>>,
     flush_c: BackgroundThread<(), Option<usize>>,
     buffer_pool: &Arc<BufferPool>,
+    freelist: &Arc<FreeList<BLOCK_SIZE>>,
   ) -> Result<Self> {
     config.max_file_size.div_assign(WAL_PAGE_SIZE);
 
@@ -69,17 +70,21 @@ impl WriteAheadLog {
       last_index,
     );
 
-    let (last_transaction, cursor) = core.replay(buffer_pool)?;
+    let (last_transaction, cursor) = core.replay(buffer_pool, freelist)?;
 
     core.buffer.initial_state(last_transaction);
-    Ok(core.start_checkpoint(flush_c).start_io(cursor))
+    Ok(
+      core
+        .start_checkpoint(flush_c, freelist)
+        .start_io(cursor, freelist),
+    )
   }
 
   fn new(
     buffer: Arc<LogBuffer>,
     commit_c: Arc<BackgroundThread<CommitInfo, Result>>,
     disk: Arc<Finder<WAL_PAGE_SIZE>>,
-    io_c: Arc<BackgroundThread<Vec<LogRecord>, Result>>,
+    io_c: Arc<BackgroundThread<Vec<LogRecord>, Result<usize>>>,
     checkpoint_c: Arc<BackgroundThread<()>>,
     config: WriteAheadLogConfig,
     last_index: Arc<RwLock<usize>>,
@@ -95,24 +100,28 @@ impl WriteAheadLog {
     }
   }
 
-  fn start_io(self, mut cursor: usize) -> Self {
+  fn start_io(self, mut cursor: usize, freelist: &Arc<FreeList<BLOCK_SIZE>>) -> Self {
     let max_file_size = self.config.max_file_size;
     let checkpoint_count = self.config.checkpoint_count;
     let disk = self.disk.clone();
     let checkpoint_c = self.checkpoint_c.clone();
     let last_index = self.last_index.clone();
     let commit_c = self.commit_c.clone();
+    let freelist = freelist.clone();
     let mut current = LogEntry::new();
     let mut counter = 0;
+    // indexes are never handed out twice, even when a batch fails.
+    let mut index = *self.last_index.rl();
 
     self.io_c.set_work(BackgroundWork::no_timeout(
       move |records: Vec<LogRecord>| {
         counter += records.len();
+        let mut commits = vec![];
         for mut record in records {
-          let mut l = last_index.wl();
-          record.assign_id(l.add(1));
+          index.add_assign(1);
+          record.assign_id(index);
           if let Operation::Commit = record.operation {
-            commit_c.send(CommitInfo::new(record.transaction_id, record.index));
+            commits.push(CommitInfo::new(record.transaction_id, record.index));
           }
 
           if !current.is_available(&record) {
@@ -121,26 +130,46 @@ impl WriteAheadLog {
             cursor = cursor.add(1).rem_euclid(max_file_size);
           }
           current.append(record);
-          l.add_assign(1);
         }
 
         disk.write(cursor, current.serialize()?)?;
 
+        // commits must be applied before new snapshots can include them.
+        for commit in commits {
+          let (tx_id, commit_index) = (commit.tx_id, commit.commit_index);
+          if let Err(err) = commit_c.send_await(commit) {
+            *last_index.wl() = commit_index.sub(1);
+            return Err(err);
+          }
+          freelist.commit(tx_id, commit_index);
+        }
+        *last_index.wl() = index;
+
         if checkpoint_count.lt(&counter) {
           checkpoint_c.send(());
           counter = 0;
         }
-        Ok(())
+        Ok(index)
       },
     ));
     self
   }
 
-  fn start_checkpoint(self, flush_c: BackgroundThread<(), Option<usize>>) -> Self {
+  fn start_checkpoint(
+    self,
+    flush_c: BackgroundThread<(), Option<usize>>,
+    freelist: &Arc<FreeList<BLOCK_SIZE>>,
+  ) -> Self {
     let io_c = self.io_c.clone();
+    let last_index = self.last_index.clone();
+    let freelist = freelist.clone();
     self.checkpoint_c.set_work(BackgroundWork::with_timeout(
       self.config.checkpoint_interval,
       move |_| {
+        let applied = *last_index.rl();
+        if let Err(err) = freelist.persist(applied) {
+          logger::error(format!("freelist persist failed {:?}", err));
+        }
         if let Some(to_be_apply) = flush_c.send_await(()) {
           io_c
             .send_await(vec![LogRecord::new_checkpoint(to_be_apply)])
@@ -159,19 +188,55 @@ impl WriteAheadLog {
     Ok(())
   }
 
+  pub fn release(&self, tx_id: usize, page_index: usize) -> Result<()> {
+    self.buffer.release(tx_id, page_index);
+    if self.buffer.len().ge(&self.config.max_buffer_size) {
+      self.io_c.send_await(self.buffer.flush())?;
+    }
+    Ok(())
+  }
+
   pub fn new_transaction(&self) -> Result<(usize, usize)> {
     let tx_id = self.buffer.new_transaction();
     if self.buffer.len().ge(&self.config.max_buffer_size) {
       self.io_c.send_await(self.buffer.flush())?;
     }
-    Ok((tx_id, *self.last_index.rl()))
+    Ok((tx_id, self.last_commit_index()))
   }
 
-  pub fn commit(&self, tx_id: usize) -> Result<()> {
+  pub fn last_commit_index(&self) -> usize {
+    *self.last_index.rl()
+  }
+
+  /// Returns the commit index, which is the last record of the batch.
+  pub fn commit(&self, tx_id: usize) -> Result<usize> {
     let records = self.buffer.commit(tx_id);
     self.io_c.send_await(records)
   }
 
+  pub fn abort(&self, tx_id: usize) -> Result<()> {
+    self.buffer.rollback(tx_id);
+    self.io_c.send_await(vec![LogRecord::new_abort(tx_id)])?;
+    Ok(())
+  }
+
+  pub fn savepoint(&self, tx_id: usize) -> usize {
+    self.buffer.savepoint(tx_id)
+  }
+
+  pub fn rollback_to(&self, tx_id: usize, savepoint: usize) -> Result<bool> {
+    self.buffer.rollback_to(tx_id, savepoint)
+  }
+
+  /// Writes out the records still buffered, as a full buffer would,
+  /// and checkpoints after them.
+  #[cfg(test)]
+  pub fn checkpoint(&self) -> Result {
+    self.io_c.send_await(self.buffer.flush())?;
+    self.checkpoint_c.send_await(());
+    Ok(())
+  }
+
   pub fn before_shutdown(&self) {
     self.checkpoint_c.send(());
     self.commit_c.close();
@@ -180,7 +245,11 @@ impl WriteAheadLog {
     self.disk.close();
   }
 
-  fn replay(&self, buffer_pool: &Arc<BufferPool>) -> Result<(usize, usize)> {
+  fn replay(
+    &self,
+    buffer_pool: &Arc<BufferPool>,
+    freelist: &Arc<FreeList<BLOCK_SIZE>>,
+  ) -> Result<(usize, usize)> {
     let mut cursor = 0;
     let mut records: BTreeMap<usize, LogRecord> = BTreeMap::new();
 
@@ -205,10 +274,12 @@ impl WriteAheadLog {
 
     let mut last_index = 0;
     let mut last_transaction = 0;
-    let mut committed = BTreeSet::new();
+    let mut committed = BTreeMap::new();
     let mut aborted = BTreeSet::new();
     let mut started = BTreeSet::new();
     let mut inserts = BTreeMap::new();
+    let mut pages = vec![];
+    let applied = freelist.applied_index();
     for record in records.into_values() {
       last_transaction = record.transaction_id.max(last_transaction);
       last_index = record.index.max(last_index);
@@ -218,7 +289,7 @@ impl WriteAheadLog {
         }
         Operation::Commit => {
           started.remove(&record.transaction_id).then(|| {
-            committed.insert(record.transaction_id);
+            committed.insert(record.transaction_id, record.index);
           });
         }
         Operation::Abort => {
@@ -227,23 +298,37 @@ impl WriteAheadLog {
           });
         }
         Operation::Checkpoint(i) => {
+          // transactions can span a checkpoint, so their state is kept.
           inserts = inserts.split_off(&i);
-          started.clear();
-          committed.clear();
-          aborted.clear();
         }
         Operation::Insert(log) => {
+          pages.push((record.transaction_id, log.page_index, false));
           inserts.insert(record.index, (record.transaction_id, log));
         }
+        Operation::Release(i) => {
+          pages.push((record.transaction_id, i, true));
+        }
+      }
+    }
+
+    // the persisted list has every commit up to applied,
+    // even when the records were logged before it.
+    for (tx_id, page_index, released) in pages {
+      match committed.get(&tx_id) {
+        Some(commit_index) if commit_index.gt(&applied) => {}
+        _ => continue,
+      };
+      match released {
+        true => freelist.insert(page_index),
+        false => freelist.remove(page_index),
       }
     }
 
     let mut to_be_rollback = vec![];
 
     for (tx_id, log) in inserts.into_values() {
-      if committed.contains(&tx_id) {
-        //TODO error occurs in here
-        buffer_pool.insert(tx_id, log.page_index, log.data)?;
+      if let Some(&commit_index) = committed.get(&tx_id) {
+        buffer_pool.replay(tx_id, commit_index, log.page_index, log.data)?;
       } else {
         to_be_rollback.push((tx_id, log.page_index))
       }
@@ -258,3 +343,55 @@ impl WriteAheadLog {
     Ok((last_transaction, cursor))
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use crate::engine::TestEngine;
+
+  #[test]
+  fn _1() {
+    let engine = TestEngine::open("wal-1");
+    engine.create_keyspace("other").unwrap();
+    let spanning = engine.new_transaction().unwrap();
+    spanning.insert(b"before".to_vec(), vec![1]).unwrap();
+
+    let cursor = engine.new_transaction().unwrap();
+    let other = cursor.keyspace("other").unwrap();
+    other.insert(b"other".to_vec(), vec![2]).unwrap();
+    cursor.commit().unwrap();
+    engine.checkpoint();
+
+    spanning.insert(b"after".to_vec(), vec![3]).unwrap();
+    spanning.commit().unwrap();
+    let aborted = engine.new_transaction().unwrap();
+    let other = aborted.keyspace("other").unwrap();
+    other.insert(b"aborted".to_vec(), vec![4]).unwrap();
+    std::mem::forget(aborted);
+
+    let engine = engine.crash();
+    let cursor = engine.new_transaction().unwrap();
+    assert_eq!(cursor.get(&b"before".to_vec()).unwrap(), vec![1]);
+    assert_eq!(cursor.get(&b"after".to_vec()).unwrap(), vec![3]);
+    let other = cursor.keyspace("other").unwrap();
+    assert_eq!(other.get(&b"other".to_vec()).unwrap(), vec![2]);
+    assert!(other.get(&b"aborted".to_vec()).is_err());
+  }
+
+  #[test]
+  fn _2() {
+    let engine = TestEngine::open("wal-2");
+    let cursor = engine.new_transaction().unwrap();
+    cursor.insert(b"large".to_vec(), vec![1; 1000]).unwrap();
+    cursor.commit().unwrap();
+    let free = engine.freelist().len();
+
+    let cursor = engine.new_transaction().unwrap();
+    assert!(cursor.delete(&b"large".to_vec()).unwrap());
+    engine.checkpoint();
+    cursor.commit().unwrap();
+    assert_eq!(engine.freelist().len(), free + 1);
+
+    let engine = engine.crash();
+    assert_eq!(engine.freelist().len(), free + 1);
+  }
+}